@@ -1,7 +1,7 @@
 use std::io::BufRead;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use ctclib::{BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, GreedyDecoder, ZeroLM};
+use ctclib::{BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, Dict, GreedyDecoder, ZeroLM};
 
 #[cfg(feature = "dhat-heap")]
 #[global_allocator]
@@ -22,21 +22,20 @@ fn load_logits() -> (usize, usize, Vec<f32>) {
 
 fn decoder_options() -> BeamSearchDecoderOptions {
     BeamSearchDecoderOptions {
-        beam_size: 100,
         beam_size_token: 2000000,
-        beam_threshold: f32::MAX,
-        lm_weight: 0.0,
+        ..Default::default()
     }
 }
 
 fn criterion_benchmark(c: &mut Criterion) {
     let (steps, n_vocab, data) = load_logits();
-    let blank = (n_vocab - 1) as i32;
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = dict.index("").unwrap();
     let mut decoder = GreedyDecoder;
     c.bench_function("GreedyDecoder", |b| {
         b.iter(|| decoder.decode(black_box(&data), black_box(steps), n_vocab, blank))
     });
-    let mut decoder = BeamSearchDecoder::new(decoder_options(), ZeroLM);
+    let mut decoder = BeamSearchDecoder::new(decoder_options(), ZeroLM).unwrap();
     c.bench_function("ZeroLM", |b| {
         #[cfg(feature = "dhat-heap")]
         let _profiler = dhat::Profiler::new_heap();
@@ -46,18 +45,26 @@ fn criterion_benchmark(c: &mut Criterion) {
 
 #[cfg(feature = "kenlm")]
 fn criterion_benchmark_kenlm(c: &mut Criterion) {
-    use ctclib::{Dict, KenLM};
+    use ctclib::KenLM;
 
     let (steps, n_vocab, data) = load_logits();
-    let blank = (n_vocab - 1) as i32;
     let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = dict.index("").unwrap();
+    // KenLM cannot score the trailing padding column that letter.dict does not name.
+    let data = data
+        .chunks(n_vocab)
+        .flat_map(|frame| &frame[..dict.len()])
+        .copied()
+        .collect::<Vec<_>>();
+    let n_vocab = dict.len();
     let mut decoder = BeamSearchDecoder::new(
         BeamSearchDecoderOptions {
             lm_weight: 0.5,
             ..decoder_options()
         },
-        KenLM::new("data/overfit.arpa", &dict),
-    );
+        KenLM::new("data/overfit.arpa", &dict).unwrap(),
+    )
+    .unwrap();
     c.bench_function("KenLM", |b| {
         b.iter(|| decoder.decode(black_box(&data), black_box(steps), n_vocab, blank))
     });
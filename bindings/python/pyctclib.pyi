@@ -1,6 +1,6 @@
 import abc
-from typing import List, Generic, TypeVar, Tuple
-from typing_extensions import Protocol
+from typing import List, Generic, Optional, TypeVar, Tuple
+from typing_extensions import Literal, Protocol
 
 import numpy as np
 
@@ -9,8 +9,15 @@ class DecoderOutput:
     score: float
     tokens: List[int]
     timesteps: List[int]
+    end_timesteps: List[int]
     am_scores: List[float]
+    confidences: List[float]
     lm_scores: List[float]
+    lm_model_scores: List[List[float]]
+    bonus_scores: List[float]
+
+    def token_seconds(self, frame_duration: float) -> List[Tuple[float, float]]:
+        ...
 
 
 class Decoder:
@@ -18,6 +25,8 @@ class Decoder:
         self, 
         data: np.ndarray, 
         blank_id: int,
+        input_kind: Literal["log_probs", "logits", "probs"] = "log_probs",
+        temperature: float = 1.0,
     ) -> List[DecoderOutput]:
         ...
 
@@ -31,6 +40,12 @@ class BeamSearchDecoderOptions:
     beam_size_token: int
     beam_threshold: float
     lm_weight: float
+    unk_score: Optional[float]
+    word_separator: Optional[int]
+    word_score: float
+    token_score: float
+    sil_score: float
+    blank_score: float
 
     def __init__(
         self,
@@ -38,6 +53,12 @@ class BeamSearchDecoderOptions:
         beam_size_token: int,
         beam_threshold: float,
         lm_weight: float,
+        unk_score: Optional[float] = None,
+        word_separator: Optional[int] = None,
+        word_score: float = 0.0,
+        token_score: float = 0.0,
+        sil_score: float = 0.0,
+        blank_score: float = 0.0,
     ) -> None:
         ...
 
@@ -53,6 +74,7 @@ class BeamSearchDecoderWithKenLM(Decoder):
         options: BeamSearchDecoderOptions,
         model_path: str,
         vocab: List[str],
+        word_separator: Optional[int] = None,
     ) -> None:
         ...
 
@@ -1,5 +1,3 @@
-use std::sync::Mutex;
-
 use numpy::{array::PyArray2, ndarray::Dimension};
 use pyo3::{exceptions, prelude::*, PyObjectProtocol};
 
@@ -12,13 +10,39 @@ struct BeamSearchDecoderOptions(ctclib::BeamSearchDecoderOptions);
 #[pymethods]
 impl BeamSearchDecoderOptions {
     #[new]
-    #[args(lm_weight = "0.0")]
-    fn new(beam_size: usize, beam_size_token: usize, beam_threshold: f32, lm_weight: f32) -> Self {
+    #[args(
+        lm_weight = "0.0",
+        unk_score = "None",
+        word_separator = "None",
+        word_score = "0.0",
+        token_score = "0.0",
+        sil_score = "0.0",
+        blank_score = "0.0"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        beam_size: usize,
+        beam_size_token: usize,
+        beam_threshold: f32,
+        lm_weight: f32,
+        unk_score: Option<f32>,
+        word_separator: Option<i32>,
+        word_score: f32,
+        token_score: f32,
+        sil_score: f32,
+        blank_score: f32,
+    ) -> Self {
         Self(ctclib::BeamSearchDecoderOptions {
             beam_size,
             beam_size_token,
             beam_threshold,
             lm_weight,
+            unk_score,
+            word_separator,
+            word_score,
+            token_score,
+            sil_score,
+            blank_score,
         })
     }
 }
@@ -50,6 +74,21 @@ impl DecoderOutput {
         self.0.lm_scores.clone()
     }
 
+    #[getter]
+    fn lm_model_scores(&self) -> Vec<Vec<f32>> {
+        self.0.lm_model_scores.clone()
+    }
+
+    #[getter]
+    fn bonus_scores(&self) -> Vec<f32> {
+        self.0.bonus_scores.clone()
+    }
+
+    #[getter]
+    fn confidences(&self) -> Vec<f32> {
+        self.0.confidences.clone()
+    }
+
     #[getter]
     fn tokens(&self) -> Vec<i32> {
         self.0.tokens.clone()
@@ -59,6 +98,15 @@ impl DecoderOutput {
     fn timesteps(&self) -> Vec<usize> {
         self.0.timesteps.clone()
     }
+
+    #[getter]
+    fn end_timesteps(&self) -> Vec<usize> {
+        self.0.end_timesteps.clone()
+    }
+
+    fn token_seconds(&self, frame_duration: f32) -> Vec<(f32, f32)> {
+        self.0.token_seconds(frame_duration)
+    }
 }
 
 #[pyproto]
@@ -78,13 +126,32 @@ impl Decoder {
         Decoder(Box::new(ctclib::GreedyDecoder))
     }
 
-    fn decode(&mut self, data: &PyArray2<f32>, blank_id: i32) -> PyResult<Vec<DecoderOutput>> {
+    #[args(input_kind = "\"log_probs\"", temperature = "1.0")]
+    fn decode(
+        &mut self,
+        data: &PyArray2<f32>,
+        blank_id: i32,
+        input_kind: &str,
+        temperature: f32,
+    ) -> PyResult<Vec<DecoderOutput>> {
+        let kind = match input_kind {
+            "log_probs" => ctclib::InputKind::LogProbs,
+            "logits" => ctclib::InputKind::Logits,
+            "probs" => ctclib::InputKind::Probs,
+            _ => {
+                return Err(exceptions::PyValueError::new_err(format!(
+                    "unknown input_kind: {}",
+                    input_kind
+                )))
+            }
+        };
         let (steps, tokens) = data.dims().into_pattern();
         let data = data.readonly();
         let data = data.as_slice()?;
         let outputs = self
             .0
-            .decode(data, steps, tokens, blank_id)
+            .decode_input(data, steps, tokens, blank_id, kind, temperature)
+            .map_err(|err| exceptions::PyValueError::new_err(format!("{}", err)))?
             .into_iter()
             .map(DecoderOutput)
             .collect::<Vec<_>>();
@@ -103,41 +170,16 @@ impl GreedyDecoder {
     }
 }
 
-struct BeamSearchDecoderWrapper<T: ctclib::LM>(Mutex<ctclib::BeamSearchDecoder<T>>);
-
-impl<T: ctclib::LM> BeamSearchDecoderWrapper<T> {
-    fn new(decoder: ctclib::BeamSearchDecoder<T>) -> Self {
-        Self(Mutex::new(decoder))
-    }
-}
-
-impl<T: ctclib::LM> ctclib::Decoder for BeamSearchDecoderWrapper<T> {
-    fn decode(
-        &mut self,
-        data: &[f32],
-        steps: usize,
-        tokens: usize,
-        blank_id: i32,
-    ) -> Vec<ctclib::DecoderOutput> {
-        self.0.lock().unwrap().decode(data, steps, tokens, blank_id)
-    }
-}
-
-unsafe impl<T: ctclib::LM> Send for BeamSearchDecoderWrapper<T> {}
-
 #[pyclass(extends=Decoder)]
 struct BeamSearchDecoder;
 
 #[pymethods]
 impl BeamSearchDecoder {
     #[new]
-    fn new(options: BeamSearchDecoderOptions) -> (Self, Decoder) {
-        (
-            BeamSearchDecoder,
-            Decoder(Box::new(BeamSearchDecoderWrapper::new(
-                ctclib::BeamSearchDecoder::new(options.0, ctclib::ZeroLM),
-            ))),
-        )
+    fn new(options: BeamSearchDecoderOptions) -> PyResult<(Self, Decoder)> {
+        let decoder = ctclib::BeamSearchDecoder::new(options.0, ctclib::ZeroLM)
+            .map_err(|err| exceptions::PyValueError::new_err(format!("{}", err)))?;
+        Ok((BeamSearchDecoder, Decoder(Box::new(decoder))))
     }
 }
 
@@ -147,20 +189,33 @@ struct BeamSearchDecoderWithKenLM;
 #[pymethods]
 impl BeamSearchDecoderWithKenLM {
     #[new]
+    #[args(word_separator = "None")]
     fn new(
         options: BeamSearchDecoderOptions,
         model_path: &str,
         labels: Vec<String>,
+        word_separator: Option<i32>,
     ) -> PyResult<(Self, Decoder)> {
         let dict = ctclib::Dict::from_entries(labels)
             .map_err(|err| exceptions::PyRuntimeError::new_err(format!("{}", err)))?;
-        let kenlm = ctclib::KenLM::new(model_path, &dict);
-        Ok((
-            BeamSearchDecoderWithKenLM,
-            Decoder(Box::new(BeamSearchDecoderWrapper::new(
-                ctclib::BeamSearchDecoder::new(options.0, kenlm),
-            ))),
-        ))
+        let kenlm = match word_separator {
+            Some(word_separator) => {
+                ctclib::KenLM::new_word_level(model_path, &dict, word_separator)
+            }
+            None => ctclib::KenLM::new(model_path, &dict),
+        }
+        .map_err(|err| match err {
+            ctclib::LMError::NotFound(_) => {
+                exceptions::PyFileNotFoundError::new_err(format!("{}", err))
+            }
+            ctclib::LMError::InvalidPath(_) | ctclib::LMError::VocabularyMismatch(_) => {
+                exceptions::PyValueError::new_err(format!("{}", err))
+            }
+            _ => exceptions::PyRuntimeError::new_err(format!("{}", err)),
+        })?;
+        let decoder = ctclib::BeamSearchDecoder::new(options.0, kenlm)
+            .map_err(|err| exceptions::PyValueError::new_err(format!("{}", err)))?;
+        Ok((BeamSearchDecoderWithKenLM, Decoder(Box::new(decoder))))
     }
 }
 
@@ -171,12 +226,9 @@ struct BeamSearchDecoderWithPyLM;
 impl BeamSearchDecoderWithPyLM {
     #[new]
     fn new(options: BeamSearchDecoderOptions, lm: PyObject) -> PyResult<(Self, Decoder)> {
-        Ok((
-            BeamSearchDecoderWithPyLM,
-            Decoder(Box::new(BeamSearchDecoderWrapper::new(
-                ctclib::BeamSearchDecoder::new(options.0, pylm::PyLM(lm)),
-            ))),
-        ))
+        let decoder = ctclib::BeamSearchDecoder::new(options.0, pylm::PyLM(lm))
+            .map_err(|err| exceptions::PyValueError::new_err(format!("{}", err)))?;
+        Ok((BeamSearchDecoderWithPyLM, Decoder(Box::new(decoder))))
     }
 }
 
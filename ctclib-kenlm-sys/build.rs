@@ -33,6 +33,7 @@ fn main() {
         .allowlist_type("lm::ngram::Config")
         .opaque_type("std::.*")
         .allowlist_function("lm_ngram_LoadVirtual.*")
+        .allowlist_function("lm_EnumerateVocab_.*")
         .allowlist_function("lm_base_Vocabulary_.*")
         .allowlist_function("lm_base_Model_.*")
         .generate()
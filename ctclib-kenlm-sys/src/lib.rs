@@ -17,3 +17,21 @@ fn test_works() {
         lm_ngram_LoadVirtual(file_path.as_ptr(), &mut config as *mut _);
     }
 }
+
+#[test]
+fn load_virtual_reports_error() {
+    use std::ffi::{CStr, CString};
+    unsafe {
+        let config = lm_ngram_Config::new();
+        let file_path = CString::new("missing.arpa").unwrap();
+        let mut error = [0 as std::os::raw::c_char; 256];
+        let model = lm_ngram_LoadVirtualOrError(
+            file_path.as_ptr(),
+            &config as *const _,
+            error.as_mut_ptr(),
+            error.len() as _,
+        );
+        assert!(model.is_null());
+        assert!(!CStr::from_ptr(error.as_ptr()).to_bytes().is_empty());
+    }
+}
@@ -1,7 +1,38 @@
+#include <cstring>
+#include <exception>
+
 #include "wrapper.h"
 
+namespace
+{
+    class CallbackEnumerateVocab : public lm::EnumerateVocab
+    {
+    public:
+        CallbackEnumerateVocab(lm_EnumerateVocab_Callback callback, void *context) : callback_(callback), context_(context) {}
+
+        void Add(lm::WordIndex index, const StringPiece &str) override
+        {
+            callback_(context_, index, str.data(), str.size());
+        }
+
+    private:
+        lm_EnumerateVocab_Callback callback_;
+        void *context_;
+    };
+}
+
 extern "C"
 {
+    lm::EnumerateVocab *lm_EnumerateVocab_new(lm_EnumerateVocab_Callback callback, void *context)
+    {
+        return new CallbackEnumerateVocab(callback, context);
+    }
+
+    void lm_EnumerateVocab_delete(lm::EnumerateVocab *enumerate)
+    {
+        delete enumerate;
+    }
+
     lm::base::Model *lm_ngram_LoadVirtual(const char *filename, const lm::ngram::Config *config)
     {
         return lm::ngram::LoadVirtual(filename, *config);
@@ -12,6 +43,23 @@ extern "C"
         return lm::ngram::LoadVirtual(filename);
     }
 
+    lm::base::Model *lm_ngram_LoadVirtualOrError(const char *filename, const lm::ngram::Config *config, char *error, size_t error_len)
+    {
+        try
+        {
+            return lm::ngram::LoadVirtual(filename, *config);
+        }
+        catch (const std::exception &e)
+        {
+            if (error_len > 0)
+            {
+                std::strncpy(error, e.what(), error_len - 1);
+                error[error_len - 1] = '\0';
+            }
+            return nullptr;
+        }
+    }
+
     lm::WordIndex lm_base_Vocabulary_BeginSentence(const lm::base::Vocabulary *vocab)
     {
         return vocab->BeginSentence();
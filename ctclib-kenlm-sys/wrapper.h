@@ -7,12 +7,20 @@
 #include "kenlm/util/mmap.hh"
 #include "kenlm/lm/config.hh"
 #include "kenlm/lm/model.hh"
+#include "kenlm/lm/enumerate_vocab.hh"
 
 // bindgen does not generate inline functions, so we need to define them here.
 extern "C"
 {
+    // Called for each word of the vocabulary while a model is loaded.
+    typedef void (*lm_EnumerateVocab_Callback)(void *context, lm::WordIndex index, const char *str, size_t len);
+    lm::EnumerateVocab *lm_EnumerateVocab_new(lm_EnumerateVocab_Callback callback, void *context);
+    void lm_EnumerateVocab_delete(lm::EnumerateVocab *enumerate);
+
     lm::base::Model *lm_ngram_LoadVirtual(const char *filename, const lm::ngram::Config *config);
     lm::base::Model *lm_ngram_LoadVirtualWithDefaultConfig(const char *filename);
+    // Returns nullptr and writes the message into `error` instead of throwing a C++ exception.
+    lm::base::Model *lm_ngram_LoadVirtualOrError(const char *filename, const lm::ngram::Config *config, char *error, size_t error_len);
 
     lm::WordIndex lm_base_Vocabulary_BeginSentence(const lm::base::Vocabulary *vocab);
     lm::WordIndex lm_base_Vocabulary_EndSentence(const lm::base::Vocabulary *vocab);
@@ -0,0 +1,221 @@
+use thiserror::Error;
+
+use crate::DecoderOutput;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum AlignError {
+    #[error("data has {0} values, which is not steps * n_vocab")]
+    InvalidShape(usize),
+    #[error("invalid token in the target: {0}")]
+    InvalidToken(i32),
+    #[error("the target needs at least {required} steps, but got {steps}")]
+    TooShort { steps: usize, required: usize },
+    #[error("no path of the target has a finite score")]
+    Infeasible,
+}
+
+/// The best CTC path of a target token sequence.
+/// The fields of each token are in parallel vectors, the same as `DecoderOutput`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Alignment {
+    /// Score of the path, which is the sum of the scores of all the frames.
+    pub score: f32,
+    /// The target token sequence.
+    pub tokens: Vec<i32>,
+    /// Timesteps of each token, where the token is emitted for the first time.
+    pub timesteps: Vec<usize>,
+    /// The timestep after the last frame of each token.
+    pub end_timesteps: Vec<usize>,
+    /// The sum of the scores of the frames of each token, not including blanks.
+    pub am_scores: Vec<f32>,
+    /// The token of each frame, including blanks.
+    pub path: Vec<i32>,
+}
+
+impl Alignment {
+    /// Returns the aligned tokens as a `DecoderOutput`, e.g. for `DecoderOutput::token_seconds` and `DecoderOutput::words`.
+    pub fn to_output(&self) -> DecoderOutput {
+        DecoderOutput {
+            score: self.score,
+            tokens: self.tokens.clone(),
+            timesteps: self.timesteps.clone(),
+            end_timesteps: self.end_timesteps.clone(),
+            am_scores: self.am_scores.clone(),
+            ..DecoderOutput::default()
+        }
+    }
+}
+
+/// Where a state of the Viterbi trellis comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transition {
+    Stay,
+    Next,
+    /// Skips the blank between two different tokens.
+    Skip,
+}
+
+/// Checks that `target` fits in `data`, and returns the labels of the CTC trellis of `target`.
+/// Blanks are interleaved with the target tokens: blank, t1, blank, t2, ..., blank.
+pub(crate) fn ctc_labels(
+    data: &[f32],
+    steps: usize,
+    n_vocab: usize,
+    blank_id: i32,
+    target: &[i32],
+) -> Result<Vec<i32>, AlignError> {
+    if data.len() != steps * n_vocab {
+        return Err(AlignError::InvalidShape(data.len()));
+    }
+    if let Some(&token) = target
+        .iter()
+        .find(|&&token| token == blank_id || token < 0 || token as usize >= n_vocab)
+    {
+        return Err(AlignError::InvalidToken(token));
+    }
+    let repeats = target.windows(2).filter(|w| w[0] == w[1]).count();
+    // At least one frame is necessary even for an empty target.
+    let required = (target.len() + repeats).max(1);
+    if steps < required {
+        return Err(AlignError::TooShort { steps, required });
+    }
+    Ok(std::iter::once(blank_id)
+        .chain(target.iter().flat_map(|&token| [token, blank_id]))
+        .collect())
+}
+
+/// Computes the best CTC path of `target` (a token sequence without blanks) by the Viterbi algorithm.
+///
+/// `data` has the same `[steps, n_vocab]` layout of log probabilities as `Decoder::decode`.
+/// The same token repeated in `target` needs a blank between them, so `steps` must be at least the length of `target` plus the number of the repeats.
+/// Fails with `AlignError::Infeasible` if every path of `target` has a score of negative infinity.
+pub fn forced_align(
+    data: &[f32],
+    steps: usize,
+    n_vocab: usize,
+    blank_id: i32,
+    target: &[i32],
+) -> Result<Alignment, AlignError> {
+    let labels = ctc_labels(data, steps, n_vocab, blank_id, target)?;
+    let n_states = labels.len();
+    let score = |t: usize, s: usize| data[t * n_vocab + labels[s] as usize];
+    let mut scores = vec![f32::NEG_INFINITY; n_states];
+    let mut next_scores = vec![f32::NEG_INFINITY; n_states];
+    let mut transitions = vec![Transition::Stay; steps * n_states];
+    scores[0] = score(0, 0);
+    if n_states > 1 {
+        scores[1] = score(0, 1);
+    }
+    for t in 1..steps {
+        for s in 0..n_states {
+            let mut best = (scores[s], Transition::Stay);
+            if s >= 1 && scores[s - 1] > best.0 {
+                best = (scores[s - 1], Transition::Next);
+            }
+            if s >= 2
+                && labels[s] != blank_id
+                && labels[s] != labels[s - 2]
+                && scores[s - 2] > best.0
+            {
+                best = (scores[s - 2], Transition::Skip);
+            }
+            next_scores[s] = best.0 + score(t, s);
+            transitions[t * n_states + s] = best.1;
+        }
+        std::mem::swap(&mut scores, &mut next_scores);
+    }
+
+    // The path ends with the last token or the blank after it.
+    let mut s = n_states - 1;
+    if n_states > 1 && scores[n_states - 2] > scores[s] {
+        s = n_states - 2;
+    }
+    if scores[s] == f32::NEG_INFINITY {
+        return Err(AlignError::Infeasible);
+    }
+    let mut output = Alignment {
+        score: scores[s],
+        tokens: target.to_vec(),
+        timesteps: vec![0; target.len()],
+        end_timesteps: vec![0; target.len()],
+        am_scores: vec![0.0; target.len()],
+        path: vec![blank_id; steps],
+    };
+    for t in (0..steps).rev() {
+        output.path[t] = labels[s];
+        if labels[s] != blank_id {
+            // The state of the i-th token is 2 * i + 1.
+            let i = s / 2;
+            if output.end_timesteps[i] == 0 {
+                output.end_timesteps[i] = t + 1;
+            }
+            output.timesteps[i] = t;
+            output.am_scores[i] += score(t, s);
+        }
+        s -= match transitions[t * n_states + s] {
+            Transition::Stay => 0,
+            Transition::Next => 1,
+            Transition::Skip => 2,
+        };
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forced_align, AlignError};
+
+    #[test]
+    fn it_aligns_target() {
+        // Tokens: 0 = A, 1 = B, 2 = blank.
+        let ln = |p: f32| p.ln();
+        #[rustfmt::skip]
+        let data = [
+            ln(0.8), ln(0.1), ln(0.1),
+            ln(0.6), ln(0.1), ln(0.3),
+            ln(0.1), ln(0.2), ln(0.7),
+            ln(0.1), ln(0.8), ln(0.1),
+        ];
+        let alignment = forced_align(&data, 4, 3, 2, &[0, 1]).unwrap();
+        assert_eq!(alignment.path, vec![0, 0, 2, 1]);
+        assert_eq!(alignment.timesteps, vec![0, 3]);
+        assert_eq!(alignment.end_timesteps, vec![2, 4]);
+        assert!((alignment.am_scores[0] - ln(0.48)).abs() < 1e-6);
+        assert!((alignment.score - ln(0.8 * 0.6 * 0.7 * 0.8)).abs() < 1e-6);
+
+        // A blank is necessary between the repeated tokens.
+        let alignment = forced_align(&data, 4, 3, 2, &[0, 0]).unwrap();
+        assert_eq!(alignment.path, vec![0, 0, 2, 0]);
+        assert_eq!(alignment.timesteps, vec![0, 3]);
+        assert_eq!(
+            alignment.to_output().token_seconds(0.5),
+            vec![(0.0, 1.0), (1.5, 2.0)]
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_targets() {
+        let data = [0.0; 6];
+        assert_eq!(
+            forced_align(&data, 2, 3, 2, &[0, 0]),
+            Err(AlignError::TooShort {
+                steps: 2,
+                required: 3
+            })
+        );
+        assert_eq!(
+            forced_align(&data, 2, 3, 2, &[2]),
+            Err(AlignError::InvalidToken(2))
+        );
+        assert_eq!(
+            forced_align(&data, 3, 3, 2, &[0]),
+            Err(AlignError::InvalidShape(6))
+        );
+        // Nothing but the blank can be emitted.
+        let data = [f32::NEG_INFINITY, f32::NEG_INFINITY, 0.0];
+        assert_eq!(
+            forced_align(&data, 1, 3, 2, &[0]),
+            Err(AlignError::Infeasible)
+        );
+    }
+}
@@ -1,8 +1,17 @@
+mod batch;
 mod beamsearch;
+mod frame_sync;
 mod greedy;
+mod lexicon_beamsearch;
+mod prefix_beamsearch;
 
-pub use beamsearch::{BeamSearchDecoder, BeamSearchDecoderOptions};
+pub use batch::BatchDecoder;
+pub use beamsearch::{BeamSearchDecoder, BeamSearchDecoderOptions, OptionsError};
 pub use greedy::GreedyDecoder;
+pub use lexicon_beamsearch::LexiconBeamSearchDecoder;
+pub use prefix_beamsearch::PrefixBeamSearchDecoder;
+
+use thiserror::Error;
 
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct DecoderOutput {
@@ -10,12 +19,24 @@ pub struct DecoderOutput {
     pub score: f32,
     /// A sequence of tokens. Note that the sequence is always shorter than the original sequence.
     pub tokens: Vec<i32>,
-    /// Timesteps of each token.
+    /// Timesteps of each token, where the token is emitted for the first time.
     pub timesteps: Vec<usize>,
+    /// The timestep after the last frame of each token, where repeats of the token end.
+    /// Blanks after the token are not included.
+    pub end_timesteps: Vec<usize>,
     /// Acoustic model scores of each token.
     pub am_scores: Vec<f32>,
+    /// Confidence of each token, which is the highest posterior probability of the token over its frames.
+    /// This takes the input as log probabilities, so it is between 0 and 1 for the output of a log-softmax.
+    pub confidences: Vec<f32>,
     /// Language model scores of each token.
     pub lm_scores: Vec<f32>,
+    /// Scores of each model fused by the language model (see `MultiLM`) for each token.
+    /// `lm_model_scores[i][j]` is the score of the j-th model for the i-th token. This is empty unless the language model fuses several models.
+    pub lm_model_scores: Vec<Vec<f32>>,
+    /// Insertion bonuses (`token_score` and `word_score`) and hotword bonuses of each token.
+    /// A hotword bonus is negative when the token abandons a phrase, and `score` also retracts a phrase left incomplete at the end.
+    pub bonus_scores: Vec<f32>,
 }
 
 impl DecoderOutput {
@@ -35,6 +56,179 @@ impl DecoderOutput {
         }
         output
     }
+
+    /// Extends the last token to the frame before `end_timestep`, where the token is repeated with the frame score `am_score`.
+    pub(crate) fn repeat_last_token(&mut self, end_timestep: usize, am_score: f32) {
+        *self.end_timesteps.last_mut().unwrap() = end_timestep;
+        let confidence = self.confidences.last_mut().unwrap();
+        *confidence = confidence.max(am_score.exp());
+    }
+
+    /// Returns the start and end time of each token in seconds, where a frame lasts `frame_duration` seconds.
+    pub fn token_seconds(&self, frame_duration: f32) -> Vec<(f32, f32)> {
+        self.timesteps
+            .iter()
+            .zip(self.end_timesteps.iter())
+            .map(|(&start, &end)| (start as f32 * frame_duration, end as f32 * frame_duration))
+            .collect()
+    }
+
+    /// Groups the tokens into words separated by `separator`, e.g. `|` of a letter dictionary.
+    /// The separators are not included in the words, and empty words are skipped.
+    /// Tokens without confidences (e.g. an output built by hand) are taken as certain, i.e. their confidences are 1.
+    pub fn words(&self, separator: i32) -> Vec<WordSpan> {
+        let mut words = Vec::new();
+        let mut word = WordSpan::default();
+        for (i, &token) in self.tokens.iter().enumerate() {
+            let confidence = self.confidences.get(i).copied().unwrap_or(1.0);
+            if token == separator {
+                if !word.tokens.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            if word.tokens.is_empty() {
+                word.start_timestep = self.timesteps[i];
+                word.confidence = confidence;
+            }
+            word.tokens.push(token);
+            word.confidence = word.confidence.min(confidence);
+            word.end_timestep = self.end_timesteps[i];
+        }
+        if !word.tokens.is_empty() {
+            words.push(word);
+        }
+        words
+    }
+}
+
+/// A word of `DecoderOutput`, which is a run of tokens between word separators.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WordSpan {
+    pub tokens: Vec<i32>,
+    /// The timestep of the first token.
+    pub start_timestep: usize,
+    /// The end timestep of the last token.
+    pub end_timestep: usize,
+    /// The lowest confidence of the tokens, so that a word with a single doubtful token is doubtful.
+    pub confidence: f32,
+}
+
+impl WordSpan {
+    /// Returns the start and end time of the word in seconds, where a frame lasts `frame_duration` seconds.
+    pub fn seconds(&self, frame_duration: f32) -> (f32, f32) {
+        (
+            self.start_timestep as f32 * frame_duration,
+            self.end_timestep as f32 * frame_duration,
+        )
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum InputError {
+    #[error("temperature must be positive and finite, but got {0}")]
+    InvalidTemperature(f32),
+    #[error("data has {len} values, which is not steps * n_vocab with n_vocab {n_vocab}")]
+    InvalidShape { len: usize, n_vocab: usize },
+    #[error("data has an invalid value {value} at {index}")]
+    InvalidValue { index: usize, value: f32 },
+    #[error("data has {len} values, which is less than batch {batch} * steps {steps} * n_vocab {n_vocab}")]
+    InvalidBatchShape {
+        len: usize,
+        batch: usize,
+        steps: usize,
+        n_vocab: usize,
+    },
+    #[error("lengths has {len} elements, which is not batch {batch}")]
+    InvalidLengths { len: usize, batch: usize },
+    #[error("length {length} of utterance {index} exceeds steps {steps}")]
+    InvalidLength {
+        index: usize,
+        length: usize,
+        steps: usize,
+    },
+}
+
+/// What the values of the input of decoders are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputKind {
+    /// Log probabilities, e.g. the output of a log-softmax. Decoders add them as scores.
+    #[default]
+    LogProbs,
+    /// Unnormalized scores, e.g. the output of the last layer before a softmax.
+    Logits,
+    /// Probabilities, e.g. the output of a softmax.
+    Probs,
+}
+
+impl InputKind {
+    /// Converts `data` of this kind to log probabilities, where each frame has `n_vocab` values.
+    ///
+    /// `temperature` divides the log probabilities (or the logits) of each frame before they are normalized again, so a value above 1 flattens the distribution and below 1 sharpens it.
+    /// Log probabilities with the temperature of 1 are returned as is.
+    /// Fails if `temperature` is not positive, `data` does not consist of frames of `n_vocab` values, or a value is invalid for the kind:
+    /// NaN or infinity for log probabilities and logits, where negative infinity is still a zero probability, and out of [0, 1] for probabilities.
+    pub fn normalize(
+        self,
+        data: &[f32],
+        n_vocab: usize,
+        temperature: f32,
+    ) -> Result<Vec<f32>, InputError> {
+        if !(temperature > 0.0 && temperature.is_finite()) {
+            return Err(InputError::InvalidTemperature(temperature));
+        }
+        if n_vocab == 0 || !data.len().is_multiple_of(n_vocab) {
+            return Err(InputError::InvalidShape {
+                len: data.len(),
+                n_vocab,
+            });
+        }
+        if let Some((index, &value)) = data
+            .iter()
+            .enumerate()
+            .find(|(_, &value)| !self.is_valid(value))
+        {
+            return Err(InputError::InvalidValue { index, value });
+        }
+        let mut output = match self {
+            InputKind::LogProbs | InputKind::Logits => data.to_vec(),
+            InputKind::Probs => data.iter().map(|p| p.ln()).collect(),
+        };
+        if self == InputKind::LogProbs && temperature == 1.0 {
+            return Ok(output);
+        }
+        for frame in output.chunks_mut(n_vocab) {
+            log_softmax(frame, temperature);
+        }
+        Ok(output)
+    }
+
+    fn is_valid(self, value: f32) -> bool {
+        match self {
+            InputKind::LogProbs | InputKind::Logits => value < f32::INFINITY,
+            InputKind::Probs => (0.0..=1.0).contains(&value),
+        }
+    }
+}
+
+/// Replaces `frame` with the log-softmax of `frame / temperature`.
+fn log_softmax(frame: &mut [f32], temperature: f32) {
+    for value in frame.iter_mut() {
+        *value /= temperature;
+    }
+    let max = frame.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return;
+    }
+    let log_sum = max
+        + frame
+            .iter()
+            .map(|value| (value - max).exp())
+            .sum::<f32>()
+            .ln();
+    for value in frame.iter_mut() {
+        *value -= log_sum;
+    }
 }
 
 /// Decoder is a trait for decoding a ctc sequence of tokens.
@@ -46,4 +240,35 @@ pub trait Decoder {
         tokens: usize,
         blank_id: i32,
     ) -> Vec<DecoderOutput>;
+
+    /// Decodes `data` of the given kind, which is converted to log probabilities by `InputKind::normalize` before `decode`.
+    /// Fails without decoding if the temperature, the shape or a value of `data` is invalid.
+    fn decode_input(
+        &mut self,
+        data: &[f32],
+        steps: usize,
+        tokens: usize,
+        blank_id: i32,
+        kind: InputKind,
+        temperature: f32,
+    ) -> Result<Vec<DecoderOutput>, InputError> {
+        if data.len() != steps * tokens {
+            return Err(InputError::InvalidShape {
+                len: data.len(),
+                n_vocab: tokens,
+            });
+        }
+        let data = kind.normalize(data, tokens, temperature)?;
+        Ok(self.decode(&data, steps, tokens, blank_id))
+    }
+}
+
+/// Returns log(exp(a) + exp(b)).
+pub(crate) fn log_add(a: f32, b: f32) -> f32 {
+    let max_score = a.max(b);
+    let min_score = a.min(b);
+    if min_score == f32::NEG_INFINITY {
+        return max_score;
+    }
+    max_score + libm::log1p(libm::exp(min_score as f64 - max_score as f64)) as f32
 }
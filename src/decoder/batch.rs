@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Decoder, DecoderOutput, InputError};
+
+/// BatchDecoder decodes a batch of utterances in parallel.
+///
+/// Each worker thread builds its own decoder by calling `factory`, so the decoder does not have to be `Send`.
+/// The factory is called once per worker for each batch, so it should be cheap (e.g. share a loaded model instead of loading it).
+/// The worker threads are also spawned for each batch rather than kept in a pool, so batches that are too small to fill the workers do not gain from the threads.
+pub struct BatchDecoder<F> {
+    factory: F,
+    num_threads: usize,
+}
+
+impl<D: Decoder, F: Fn() -> D + Sync> BatchDecoder<F> {
+    pub fn new(factory: F, num_threads: usize) -> Self {
+        Self {
+            factory,
+            num_threads: num_threads.max(1),
+        }
+    }
+
+    /// Decodes each utterance of `data`, whose layout is `[batch, steps, tokens]`.
+    /// Only the first `lengths[i]` steps of the i-th utterance are decoded.
+    /// Returns the outputs of `Decoder::decode` for each utterance in the order of the batch.
+    /// Fails without decoding if `data` is shorter than the layout, or `lengths` does not have a length of at most `steps` for each utterance.
+    pub fn decode_batch(
+        &self,
+        data: &[f32],
+        batch: usize,
+        steps: usize,
+        tokens: usize,
+        lengths: &[usize],
+        blank_id: i32,
+    ) -> Result<Vec<Vec<DecoderOutput>>, InputError> {
+        if data.len() < batch * steps * tokens {
+            return Err(InputError::InvalidBatchShape {
+                len: data.len(),
+                batch,
+                steps,
+                n_vocab: tokens,
+            });
+        }
+        if lengths.len() != batch {
+            return Err(InputError::InvalidLengths {
+                len: lengths.len(),
+                batch,
+            });
+        }
+        if let Some((index, &length)) = lengths
+            .iter()
+            .enumerate()
+            .find(|(_, &length)| length > steps)
+        {
+            return Err(InputError::InvalidLength {
+                index,
+                length,
+                steps,
+            });
+        }
+        let next_index = AtomicUsize::new(0);
+        let mut outputs = vec![Vec::new(); batch];
+        std::thread::scope(|scope| {
+            let workers = (0..self.num_threads.min(batch))
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut decoder = (self.factory)();
+                        let mut worker_outputs = Vec::new();
+                        loop {
+                            let i = next_index.fetch_add(1, Ordering::Relaxed);
+                            if i >= batch {
+                                break;
+                            }
+                            let offset = i * steps * tokens;
+                            let utterance = &data[offset..offset + lengths[i] * tokens];
+                            let output = decoder.decode(utterance, lengths[i], tokens, blank_id);
+                            worker_outputs.push((i, output));
+                        }
+                        worker_outputs
+                    })
+                })
+                .collect::<Vec<_>>();
+            for worker in workers {
+                for (i, output) in worker.join().unwrap() {
+                    outputs[i] = output;
+                }
+            }
+        });
+        Ok(outputs)
+    }
+}
@@ -1,40 +1,17 @@
-use ordered_float::OrderedFloat;
-
-use super::{Decoder, DecoderOutput};
-use crate::lm::{LMStateRef, LM};
-
-#[derive(Debug, PartialEq)]
-struct DecoderState<T> {
-    score: f32,
-    token: i32,
-    prev_blank: bool,
-    am_score: f32,
-    lm_score: f32,
-    parent_index: isize,
-    lm_state: LMStateRef<T>,
-}
+use super::{
+    frame_sync::{FrameSyncSearch, Unconstrained},
+    Decoder, DecoderOutput,
+};
+use crate::{
+    hotwords::Hotwords,
+    lm::{LMStateRef, LM},
+};
+use thiserror::Error;
 
-impl<T> Clone for DecoderState<T> {
-    fn clone(&self) -> Self {
-        Self {
-            score: self.score,
-            token: self.token,
-            prev_blank: self.prev_blank,
-            am_score: self.am_score,
-            lm_score: self.lm_score,
-            parent_index: self.parent_index,
-            lm_state: self.lm_state.clone(),
-        }
-    }
-}
-
-impl<T> DecoderState<T> {
-    /// Compare two states by their internal conditions ignoring the scores.
-    fn is_same_lm_state(&self, other: &DecoderState<T>) -> bool {
-        self.lm_state == other.lm_state
-            && self.token == other.token
-            && self.prev_blank == other.prev_blank
-    }
+#[derive(Error, Debug, PartialEq)]
+pub enum OptionsError {
+    #[error("word_separator {options} of the options differs from {lm} of the language model")]
+    WordSeparatorMismatch { options: i32, lm: i32 },
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -45,18 +22,107 @@ pub struct BeamSearchDecoderOptions {
     pub beam_threshold: f32,
     /// weight of the language model score.
     pub lm_weight: f32,
+    /// the score used instead of the weighted language model score when the language model scores a token as unknown.
+    /// `None` uses the score of the language model as is.
+    pub unk_score: Option<f32>,
+    /// the token that separates words, e.g. `|` of a letter dictionary. `word_score` is added when it is emitted.
+    /// `None` takes the word separator of the language model, e.g. `ArpaLM::new_word_level`. Decoders fail to be created if the two differ.
+    pub word_separator: Option<i32>,
+    /// the bonus added for each emitted word separator.
+    pub word_score: f32,
+    /// the bonus added for each emitted non-blank token.
+    pub token_score: f32,
+    /// the offset added to the acoustic score of the word separator at every frame. This needs `word_separator`.
+    pub sil_score: f32,
+    /// the offset added to the acoustic score of blank at every frame.
+    /// A negative value counteracts an acoustic model that is overconfident on blanks.
+    pub blank_score: f32,
+}
+
+/// The defaults disable the language model and all the bonuses, so that only `beam_size` and `beam_size_token` limit the search.
+impl Default for BeamSearchDecoderOptions {
+    fn default() -> Self {
+        Self {
+            beam_size: 100,
+            beam_size_token: 1000,
+            beam_threshold: f32::MAX,
+            lm_weight: 0.0,
+            unk_score: None,
+            word_separator: None,
+            word_score: 0.0,
+            token_score: 0.0,
+            sil_score: 0.0,
+            blank_score: 0.0,
+        }
+    }
+}
+
+impl BeamSearchDecoderOptions {
+    /// Takes the word separator of `lm` if the options do not set one.
+    /// Fails if the options and `lm` set different word separators.
+    pub(crate) fn with_word_separator_of<T: LM>(mut self, lm: &T) -> Result<Self, OptionsError> {
+        match (self.word_separator, lm.word_separator()) {
+            (Some(options), Some(lm)) if options != lm => {
+                return Err(OptionsError::WordSeparatorMismatch { options, lm })
+            }
+            (None, lm_word_separator) => self.word_separator = lm_word_separator,
+            _ => {}
+        }
+        Ok(self)
+    }
+
+    /// Returns the score added to a hypothesis for a token that the language model scores `lm_score`.
+    pub(crate) fn lm_contribution(&self, lm_score: f32, is_unknown: bool) -> f32 {
+        match self.unk_score {
+            Some(unk_score) if is_unknown => unk_score,
+            _ => self.lm_weight * lm_score,
+        }
+    }
+
+    /// Scores the end of the sentence after `state`, and returns the final state, the language model score and the score added to a hypothesis.
+    /// An unknown word left unfinished is ended by the word separator first, so that `unk_score` replaces the score of the word but not of the end of the sentence.
+    pub(crate) fn lm_finish<T: LM>(
+        &self,
+        lm: &mut T,
+        state: &LMStateRef<T::State>,
+        n_vocab: usize,
+    ) -> (LMStateRef<T::State>, f32, f32) {
+        if let Some(separator) = lm.word_separator() {
+            if self.unk_score.is_some() && lm.is_unknown(state, separator) {
+                let (word_state, word_score) = lm.score(state, separator, n_vocab);
+                let (lm_state, eos_score) = lm.finish(&word_state);
+                let contribution =
+                    self.lm_contribution(word_score, true) + self.lm_weight * eos_score;
+                return (lm_state, word_score + eos_score, contribution);
+            }
+        }
+        let (lm_state, lm_score) = lm.finish(state);
+        (lm_state, lm_score, self.lm_weight * lm_score)
+    }
+
+    /// Returns the offset added to the acoustic score of `token` at a frame.
+    pub(crate) fn frame_offset(&self, token: i32, blank_id: i32) -> f32 {
+        if token == blank_id {
+            self.blank_score
+        } else if self.word_separator == Some(token) {
+            self.sil_score
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the insertion bonus added to a hypothesis when `token` is emitted.
+    pub(crate) fn insertion_bonus(&self, token: i32) -> f32 {
+        if self.word_separator == Some(token) {
+            self.token_score + self.word_score
+        } else {
+            self.token_score
+        }
+    }
 }
 
 pub struct BeamSearchDecoder<T: LM> {
-    options: BeamSearchDecoderOptions,
-    /// All the new candidates that proposed based on the previous step.
-    current_candidates: Vec<DecoderState<T::State>>,
-    current_best_score: f32,
-    current_candidate_pointers: Vec<usize>,
-    /// hypothesis for each time step.
-    hypothesis: Vec<Vec<DecoderState<T::State>>>,
-    /// The language model.
-    lm: T,
+    search: FrameSyncSearch<T, Unconstrained>,
 }
 
 impl<T: LM> Decoder for BeamSearchDecoder<T> {
@@ -67,282 +133,119 @@ impl<T: LM> Decoder for BeamSearchDecoder<T> {
         tokens: usize,
         blank_id: i32,
     ) -> Vec<DecoderOutput> {
-        self.decode_begin(blank_id);
-        self.decode_step(data, steps, tokens, blank_id);
-        self.decode_end(steps, blank_id);
-        let mut outputs = self.get_all_hypothesis(steps, blank_id);
-        outputs.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse());
-        outputs
+        self.begin(blank_id);
+        self.search.step(data, steps, tokens);
+        self.finish()
     }
 }
 
 impl<T: LM> BeamSearchDecoder<T> {
-    pub fn new(options: BeamSearchDecoderOptions, lm: T) -> Self {
-        Self {
-            options,
-            current_candidates: Vec::new(),
-            current_best_score: f32::MIN,
-            current_candidate_pointers: Vec::new(),
-            hypothesis: Vec::new(),
-            lm,
-        }
+    /// Fails if `options` and `lm` set different word separators.
+    pub fn new(options: BeamSearchDecoderOptions, lm: T) -> Result<Self, OptionsError> {
+        Ok(Self {
+            search: FrameSyncSearch::new(options, Unconstrained, lm)?,
+        })
     }
 
-    fn decode_begin(&mut self, blank_id: i32) {
-        self.reset_candidate();
-        let initial_state = self.lm.start();
-        self.hypothesis.clear();
-        self.hypothesis.push(Vec::new());
-        self.hypothesis[0].push(DecoderState {
-            score: 0.0,
-            token: blank_id,
-            prev_blank: false,
-            am_score: 0.0,
-            lm_score: 0.0,
-            parent_index: -1, /* ROOT */
-            lm_state: initial_state,
-        });
+    /// Boosts the phrases of `hotwords` in the hypotheses.
+    pub fn with_hotwords(mut self, hotwords: Hotwords) -> Self {
+        self.search.set_hotwords(hotwords);
+        self
     }
 
-    fn decode_step(&mut self, data: &[f32], steps: usize, n_vocab: usize, blank_id: i32) {
-        // Reserve hypothesis buffer.
-        while self.hypothesis.len() < steps + 2 {
-            self.hypothesis
-                .push(Vec::with_capacity(self.options.beam_size));
-        }
-
-        // Loop over time steps.
-        let mut target_index = (0..n_vocab).collect::<Vec<_>>();
-        for t in 0..steps {
-            if n_vocab > self.options.beam_size_token {
-                // Collect tokens with the high score at the top `beam_size_token`.
-                pdqselect::select_by(&mut target_index, self.options.beam_size_token, |&a, &b| {
-                    data[t * n_vocab + a]
-                        .partial_cmp(&data[t * n_vocab + b])
-                        .unwrap()
-                        .reverse()
-                });
-            }
-            self.reset_candidate();
-            for (prev_hyp_idx, prev_hyp) in self.hypothesis[t].iter().enumerate() {
-                let prev_token = prev_hyp.token;
-                let prev_lm_state = &prev_hyp.lm_state;
-                let states =
-                    target_index
-                        .iter()
-                        .take(self.options.beam_size_token)
-                        .map(|&target| {
-                            let token = target as i32;
-                            let am_score = data[t * n_vocab + target];
-                            let score = prev_hyp.score + am_score;
-
-                            if token != blank_id && (token != prev_token || prev_hyp.prev_blank) {
-                                // New token
-                                let (lm_state, lm_score) =
-                                    self.lm.score(prev_lm_state, token, n_vocab);
-                                DecoderState {
-                                    score: score + self.options.lm_weight * lm_score,
-                                    token,
-                                    prev_blank: false,
-                                    am_score,
-                                    lm_score,
-                                    parent_index: prev_hyp_idx as isize,
-                                    lm_state,
-                                }
-                            } else if token == blank_id {
-                                // Blank
-                                DecoderState {
-                                    score,
-                                    token,
-                                    prev_blank: true,
-                                    am_score,
-                                    lm_score: prev_hyp.lm_score,
-                                    parent_index: prev_hyp_idx as isize,
-                                    lm_state: prev_lm_state.clone(),
-                                }
-                            } else {
-                                // Extend
-                                DecoderState {
-                                    score,
-                                    token,
-                                    prev_blank: false,
-                                    am_score,
-                                    lm_score: prev_hyp.lm_score,
-                                    parent_index: prev_hyp_idx as isize,
-                                    lm_state: prev_lm_state.clone(),
-                                }
-                            }
-                        });
-                for state in states {
-                    add_candidate(
-                        &mut self.current_candidates,
-                        &mut self.current_best_score,
-                        self.options.beam_threshold,
-                        state,
-                    )
-                }
-            }
-            // Finalize candidates.
-            self.finalize_candidate(t);
-        }
+    /// Starts decoding a stream.
+    /// Frames are fed by `push_frames` chunk by chunk, and `finish` returns the final hypotheses.
+    pub fn begin(&mut self, blank_id: i32) {
+        self.search.begin(blank_id);
     }
 
-    fn decode_end(&mut self, steps: usize, blank_id: i32) {
-        self.reset_candidate();
-        for (prev_hyp_idx, prev_hyp) in self.hypothesis[steps].iter().enumerate() {
-            let prev_lm_state = &prev_hyp.lm_state;
-            let (lm_state, lm_score) = self.lm.finish(prev_lm_state);
-            add_candidate(
-                &mut self.current_candidates,
-                &mut self.current_best_score,
-                self.options.beam_threshold,
-                DecoderState {
-                    score: prev_hyp.score + self.options.lm_weight * lm_score,
-                    token: blank_id,
-                    prev_blank: false,
-                    am_score: prev_hyp.am_score,
-                    lm_score: prev_hyp.lm_score + lm_score,
-                    parent_index: prev_hyp_idx as isize,
-                    lm_state,
-                },
-            );
+    /// Decodes the next `n_frames` frames of the stream.
+    /// `data` has the same `[n_frames, n_vocab]` layout as `Decoder::decode`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `begin` has not been called, or the length of `data` is not a multiple of `n_frames`.
+    pub fn push_frames(&mut self, data: &[f32], n_frames: usize) {
+        if n_frames == 0 {
+            return;
         }
-        self.finalize_candidate(steps);
+        assert_eq!(
+            data.len() % n_frames,
+            0,
+            "data must have the same number of values for each frame"
+        );
+        let n_vocab = data.len() / n_frames;
+        self.search.step(data, n_frames, n_vocab);
     }
 
-    fn reset_candidate(&mut self) {
-        self.current_best_score = f32::MIN;
-        self.current_candidates.clear();
-        self.current_candidate_pointers.clear();
+    /// Returns the best hypothesis of the frames decoded so far, which is empty before `begin`.
+    /// Unlike `finish`, the score does not include the LM score of the end of the sentence.
+    pub fn partial_best(&self) -> DecoderOutput {
+        self.search.best()
     }
 
-    /// Finalize candidates at the current time step.
-    /// This prunes the candidates, sort them by score and store them into hyp[t + 1].
-    fn finalize_candidate(&mut self, t: usize) {
-        // 1. Gather valid candidates.
-        // ================================================================
-        for (i, candidate) in self.current_candidates.iter().enumerate() {
-            if candidate.score > self.current_best_score - self.options.beam_threshold {
-                self.current_candidate_pointers.push(i);
-            }
-        }
-
-        // 2. Merge same patterns.
-        // ================================================================
-        // Sort candidates so that the same patterns are consecutive.
-        self.current_candidate_pointers.sort_by_key(|a| {
-            let x = &self.current_candidates[*a];
-            (&x.lm_state, x.token, x.prev_blank, OrderedFloat(x.score))
-        });
-        let mut n_candidates_after_merged = 1;
-        let mut last_ptr = self.current_candidate_pointers[0];
-        for i in 1..self.current_candidate_pointers.len() {
-            let ptr = self.current_candidate_pointers[i];
-            if !self.current_candidates[ptr].is_same_lm_state(&self.current_candidates[last_ptr]) {
-                // Distinct pattern.
-                self.current_candidate_pointers[n_candidates_after_merged] = ptr;
-                n_candidates_after_merged += 1;
-                last_ptr = ptr;
-            } else {
-                // Same pattern.
-                let (last, current) = {
-                    if last_ptr < ptr {
-                        let (head, tail) = self.current_candidates.split_at_mut(ptr);
-                        (&mut head[last_ptr], &mut tail[0])
-                    } else {
-                        let (head, tail) = self.current_candidates.split_at_mut(last_ptr);
-                        (&mut tail[0], &mut head[ptr])
-                    }
-                };
-                let max_score = last.score.max(current.score);
-                let min_score = last.score.min(current.score);
-                last.score =
-                    max_score + libm::log1p(libm::exp(min_score as f64 - max_score as f64)) as f32;
-            }
-        }
-        self.current_candidate_pointers
-            .truncate(n_candidates_after_merged);
-
-        // 3. Sort candidates.
-        if self.current_candidate_pointers.len() > self.options.beam_size {
-            pdqselect::select_by_key(
-                &mut self.current_candidate_pointers,
-                self.options.beam_size,
-                |&x| OrderedFloat(-self.current_candidates[x].score),
-            );
-        }
-
-        // 4. Copy candidates to output.
-        let output = &mut self.hypothesis[t + 1];
-        output.clear();
-        for &ptr in self
-            .current_candidate_pointers
-            .iter()
-            .take(self.options.beam_size)
-        {
-            output.push(self.current_candidates[ptr].clone());
-        }
+    /// Returns the output that all the surviving hypotheses share.
+    /// Only its tokens and their timesteps are final: the following frames may still repeat the last token, which extends its end timestep and confidence.
+    pub fn stable_prefix(&self) -> DecoderOutput {
+        self.search.stable_prefix()
     }
 
-    fn get_all_hypothesis(&self, final_step: usize, blank_id: i32) -> Vec<DecoderOutput> {
-        self.hypothesis[final_step + 1]
-            .iter()
-            .map(|hyp| {
-                let mut output = DecoderOutput::new();
-                output.score = hyp.score;
-                let mut hyps = Vec::with_capacity(final_step + 1);
-                let mut hyp_ = hyp;
-                for i in (0..final_step + 1).rev() {
-                    hyps.push(hyp_.clone());
-                    hyp_ = &self.hypothesis[i][hyp_.parent_index as usize];
-                    if hyp_.parent_index == -1 {
-                        break;
-                    }
-                }
-                let mut last_token = blank_id;
-                for (step, hyp) in hyps.into_iter().rev().enumerate() {
-                    let token = hyp.token;
-                    if last_token != token && token != blank_id {
-                        output.tokens.push(token);
-                        output.timesteps.push(step);
-                        output.am_scores.push(hyp.am_score);
-                        output.lm_scores.push(hyp.lm_score);
-                    }
-                    last_token = token;
-                }
-                output
-            })
-            .collect()
+    /// Drops the history of the time steps up to the stable prefix.
+    /// The outputs keep the tokens of the dropped time steps, but the memory no longer grows with the length of the stream.
+    pub fn prune_stable(&mut self) {
+        self.search.prune_stable();
     }
-}
 
-fn add_candidate<T>(
-    output: &mut Vec<DecoderState<T>>,
-    current_best_score: &mut f32,
-    beam_threshold: f32,
-    state: DecoderState<T>,
-) {
-    if state.score > *current_best_score {
-        *current_best_score = state.score;
-    }
-    if state.score > *current_best_score - beam_threshold {
-        output.push(state);
+    /// Finishes decoding the stream, and returns all the hypotheses sorted by their scores.
+    /// No hypothesis is returned before `begin`.
+    pub fn finish(&mut self) -> Vec<DecoderOutput> {
+        self.search.finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{lm::ZeroLM, BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput};
+    use crate::{
+        lm::{LMStateRef, ZeroLM, LM},
+        BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput, Hotwords,
+    };
+
+    /// A language model that knows every token except 0.
+    struct UnknownZeroLM;
+
+    impl LM for UnknownZeroLM {
+        type State = ();
+
+        fn start(&mut self) -> LMStateRef<Self::State> {
+            LMStateRef::new(())
+        }
+
+        fn score(
+            &mut self,
+            state: &LMStateRef<Self::State>,
+            token: i32,
+            n_vocab: usize,
+        ) -> (LMStateRef<Self::State>, f32) {
+            (state.child(token, n_vocab, ()), 0.0)
+        }
+
+        fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+            (state.clone(), 0.0)
+        }
+
+        fn is_unknown(&mut self, _state: &LMStateRef<Self::State>, token: i32) -> bool {
+            token == 0
+        }
+    }
 
     #[test]
     fn it_works() {
         let options = BeamSearchDecoderOptions {
             beam_size: 1,
             beam_size_token: 10,
-            beam_threshold: f32::MAX,
-            lm_weight: 0.0,
+            ..Default::default()
         };
-        let mut decoder = BeamSearchDecoder::new(options, ZeroLM);
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
         let steps = 3;
         let tokens = 4;
         #[rustfmt::skip]
@@ -359,9 +262,202 @@ mod tests {
                 score: 4.0,
                 tokens: vec![0, 1],
                 timesteps: vec![0, 2],
+                end_timesteps: vec![2, 3],
                 am_scores: vec![1.0, 2.0],
+                confidences: vec![1.0f32.exp(), 2.0f32.exp()],
                 lm_scores: vec![0.0, 0.0],
+                lm_model_scores: vec![],
+                bonus_scores: vec![0.0, 0.0],
             }
         )
     }
+
+    #[test]
+    fn it_penalizes_unknown_tokens() {
+        #[rustfmt::skip]
+        let data = &[
+            2.0, 1.0, 0.0,
+        ];
+        let mut options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            lm_weight: 1.0,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options.clone(), UnknownZeroLM).unwrap();
+        assert_eq!(decoder.decode(data, 1, 3, 2)[0].tokens, vec![0]);
+
+        options.unk_score = Some(-5.0);
+        let mut decoder = BeamSearchDecoder::new(options, UnknownZeroLM).unwrap();
+        let outputs = decoder.decode(data, 1, 3, 2);
+        assert_eq!(outputs[0].tokens, vec![1]);
+        assert_eq!(outputs[0].score, 1.0);
+    }
+
+    #[test]
+    fn it_adds_insertion_bonuses() {
+        // Tokens: 0 = A, 1 = word separator, 2 = blank.
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.0, 1.2,
+            0.0, 1.0, 1.2,
+        ];
+        let mut options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            word_separator: Some(1),
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap();
+        assert!(decoder.decode(data, 2, 3, 2)[0].tokens.is_empty());
+
+        options.token_score = 0.25;
+        options.word_score = 0.5;
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        let outputs = decoder.decode(data, 2, 3, 2);
+        assert_eq!(outputs[0].tokens, vec![0, 1]);
+        assert_eq!(outputs[0].bonus_scores, vec![0.25, 0.75]);
+        assert_eq!(outputs[0].score, 3.0);
+    }
+
+    #[test]
+    fn it_offsets_blank_scores() {
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 1.5,
+            1.0, 1.5,
+        ];
+        let mut options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap();
+        assert!(decoder.decode(data, 2, 2, 1)[0].tokens.is_empty());
+
+        options.blank_score = -1.0;
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        let outputs = decoder.decode(data, 2, 2, 1);
+        assert_eq!(outputs[0].tokens, vec![0]);
+        assert_eq!(outputs[0].am_scores, vec![1.0]);
+        assert_eq!(outputs[0].score, 2.0);
+    }
+
+    #[test]
+    fn it_boosts_hotwords() {
+        // Tokens: 0 = A, 1 = B, 2 = blank.
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 1.2, 0.0,
+        ];
+        let options = BeamSearchDecoderOptions {
+            beam_size: 2,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut hotwords = Hotwords::new();
+        hotwords.add_phrase(&[0], 0.5).unwrap();
+        let mut decoder = BeamSearchDecoder::new(options.clone(), ZeroLM)
+            .unwrap()
+            .with_hotwords(hotwords);
+        let outputs = decoder.decode(data, 1, 3, 2);
+        assert_eq!(outputs[0].tokens, vec![0]);
+        assert_eq!(outputs[0].bonus_scores, vec![0.5]);
+        assert_eq!(outputs[0].score, 1.5);
+
+        // The bonus of A is retracted since A B is not complete.
+        let mut hotwords = Hotwords::new();
+        hotwords.add_phrase(&[0, 1], 0.5).unwrap();
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM)
+            .unwrap()
+            .with_hotwords(hotwords);
+        let outputs = decoder.decode(data, 1, 3, 2);
+        assert_eq!(outputs[0].tokens, vec![1]);
+        assert_eq!(outputs[0].score, 1.2);
+        assert_eq!(outputs[1].tokens, vec![0]);
+        assert_eq!(outputs[1].score, 1.0);
+    }
+
+    #[test]
+    fn it_decodes_stream() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.0, 0.0, 0.0,
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 2.0, 0.0, 0.0,
+        ];
+        decoder.begin(3);
+        assert_eq!(decoder.partial_best(), DecoderOutput::default());
+        decoder.push_frames(&data[..8], 2);
+        let partial = decoder.partial_best();
+        assert_eq!(partial.tokens, vec![0]);
+        assert_eq!(partial.score, 2.0);
+        decoder.push_frames(&data[8..], 1);
+        let outputs = decoder.finish();
+        assert_eq!(outputs, decoder.decode(data, 3, 4, 3));
+    }
+
+    #[test]
+    fn it_returns_empty_output_before_begin() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        assert_eq!(decoder.partial_best(), DecoderOutput::default());
+        assert_eq!(decoder.stable_prefix(), DecoderOutput::default());
+        decoder.prune_stable();
+        assert!(decoder.finish().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "the same number of values for each frame")]
+    fn it_rejects_ragged_frames() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        decoder.begin(2);
+        decoder.push_frames(&[0.0; 7], 2);
+    }
+
+    #[test]
+    fn it_prunes_stable_prefix() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 2,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = BeamSearchDecoder::new(options, ZeroLM).unwrap();
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.5, 0.0, 0.0,
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 2.0, 0.0, 1.9,
+            0.0, 2.0, 0.0, 0.5,
+        ];
+        decoder.begin(3);
+        decoder.push_frames(&data[..8], 2);
+        assert!(decoder.stable_prefix().tokens.is_empty());
+        decoder.push_frames(&data[8..], 2);
+        let stable = decoder.stable_prefix();
+        assert_eq!(stable.tokens, vec![0]);
+        assert_eq!(stable.timesteps, vec![0]);
+        assert_eq!(stable.end_timesteps, vec![2]);
+
+        decoder.prune_stable();
+        assert_eq!(decoder.search.steps, 2);
+        assert_eq!(decoder.stable_prefix(), stable);
+        let outputs = decoder.finish();
+        assert_eq!(outputs, decoder.decode(data, 4, 4, 3));
+    }
 }
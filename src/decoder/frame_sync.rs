@@ -0,0 +1,509 @@
+use ordered_float::OrderedFloat;
+
+use super::{log_add, BeamSearchDecoderOptions, DecoderOutput, OptionsError};
+use crate::{
+    hotwords::Hotwords,
+    lm::{LMStateRef, LM},
+};
+
+/// A constraint on the token sequences that `FrameSyncSearch` emits, e.g. the words of a lexicon.
+/// Each hypothesis holds a state of the constraint, and hypotheses are merged only if their states are the same.
+pub(crate) trait Constraint {
+    type State: Copy + Ord;
+    /// Returns the state before any token.
+    fn start(&self) -> Self::State;
+    /// Calls `f` with each state reached by emitting `token` in `state`.
+    /// A token that breaks the constraint calls it with none, and an ambiguous token (e.g. the end of a word that is also a prefix of a longer word) with several.
+    fn next(&self, state: Self::State, token: i32, f: impl FnMut(Self::State));
+    /// Returns true if a hypothesis may end in `state`.
+    fn is_final(&self, state: Self::State) -> bool;
+}
+
+/// Any token sequence is allowed.
+pub(crate) struct Unconstrained;
+
+impl Constraint for Unconstrained {
+    type State = ();
+
+    fn start(&self) -> Self::State {}
+
+    fn next(&self, state: Self::State, _token: i32, mut f: impl FnMut(Self::State)) {
+        f(state)
+    }
+
+    fn is_final(&self, _state: Self::State) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct DecoderState<T, C> {
+    score: f32,
+    token: i32,
+    prev_blank: bool,
+    am_score: f32,
+    lm_score: f32,
+    parent_index: isize,
+    lm_state: LMStateRef<T>,
+    /// Insertion and hotword bonuses of the token.
+    bonus: f32,
+    /// The position in the hotword automaton.
+    hotword_node: usize,
+    /// The state of the constraint.
+    constraint_state: C,
+}
+
+impl<T, C: Copy> Clone for DecoderState<T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            score: self.score,
+            token: self.token,
+            prev_blank: self.prev_blank,
+            am_score: self.am_score,
+            lm_score: self.lm_score,
+            parent_index: self.parent_index,
+            lm_state: self.lm_state.clone(),
+            bonus: self.bonus,
+            hotword_node: self.hotword_node,
+            constraint_state: self.constraint_state,
+        }
+    }
+}
+
+impl<T, C: PartialEq> DecoderState<T, C> {
+    /// Compare two states by their internal conditions ignoring the scores.
+    fn is_same_state(&self, other: &DecoderState<T, C>) -> bool {
+        self.lm_state == other.lm_state
+            && self.hotword_node == other.hotword_node
+            && self.constraint_state == other.constraint_state
+            && self.token == other.token
+            && self.prev_blank == other.prev_blank
+    }
+}
+
+/// The frame-synchronous beam search shared by `BeamSearchDecoder` and `LexiconBeamSearchDecoder`.
+/// It keeps the best paths of each frame, and the constraint decides which tokens a hypothesis can emit.
+pub(crate) struct FrameSyncSearch<T: LM, C: Constraint> {
+    options: BeamSearchDecoderOptions,
+    constraint: C,
+    /// All the new candidates that proposed based on the previous step.
+    current_candidates: Vec<DecoderState<T::State, C::State>>,
+    current_best_score: f32,
+    current_candidate_pointers: Vec<usize>,
+    /// hypothesis for each time step.
+    hypothesis: Vec<Vec<DecoderState<T::State, C::State>>>,
+    /// The number of time steps in `hypothesis`, not including the pruned ones.
+    pub(crate) steps: usize,
+    /// The number of time steps pruned by `prune_stable`.
+    pruned_steps: usize,
+    /// The output of the pruned time steps.
+    committed: DecoderOutput,
+    blank_id: i32,
+    /// The number of tokens of the frames, which `finish` needs to score a word separator.
+    n_vocab: usize,
+    hotwords: Hotwords,
+    /// The language model.
+    lm: T,
+}
+
+impl<T: LM, C: Constraint> FrameSyncSearch<T, C> {
+    pub(crate) fn new(
+        options: BeamSearchDecoderOptions,
+        constraint: C,
+        lm: T,
+    ) -> Result<Self, OptionsError> {
+        Ok(Self {
+            options: options.with_word_separator_of(&lm)?,
+            constraint,
+            current_candidates: Vec::new(),
+            current_best_score: f32::MIN,
+            current_candidate_pointers: Vec::new(),
+            hypothesis: Vec::new(),
+            steps: 0,
+            pruned_steps: 0,
+            committed: DecoderOutput::new(),
+            blank_id: 0,
+            n_vocab: 0,
+            hotwords: Hotwords::new(),
+            lm,
+        })
+    }
+
+    pub(crate) fn set_hotwords(&mut self, mut hotwords: Hotwords) {
+        hotwords.build();
+        self.hotwords = hotwords;
+    }
+
+    pub(crate) fn begin(&mut self, blank_id: i32) {
+        self.reset_candidate();
+        self.steps = 0;
+        self.pruned_steps = 0;
+        self.committed = DecoderOutput::new();
+        self.blank_id = blank_id;
+        let initial_state = self.lm.start();
+        self.hypothesis.clear();
+        self.hypothesis.push(Vec::new());
+        self.hypothesis[0].push(DecoderState {
+            score: 0.0,
+            token: blank_id,
+            prev_blank: false,
+            am_score: 0.0,
+            lm_score: 0.0,
+            parent_index: -1, /* ROOT */
+            lm_state: initial_state,
+            bonus: 0.0,
+            hotword_node: Hotwords::ROOT,
+            constraint_state: self.constraint.start(),
+        });
+    }
+
+    pub(crate) fn step(&mut self, data: &[f32], steps: usize, n_vocab: usize) {
+        assert!(
+            self.is_started(),
+            "begin must be called before decoding frames"
+        );
+        let blank_id = self.blank_id;
+        self.n_vocab = n_vocab;
+        // Reserve hypothesis buffer.
+        while self.hypothesis.len() < self.steps + steps + 2 {
+            self.hypothesis
+                .push(Vec::with_capacity(self.options.beam_size));
+        }
+
+        // Loop over time steps.
+        let mut target_index = (0..n_vocab).collect::<Vec<_>>();
+        for t in 0..steps {
+            if n_vocab > self.options.beam_size_token {
+                // Collect tokens with the high score at the top `beam_size_token`.
+                pdqselect::select_by(&mut target_index, self.options.beam_size_token, |&a, &b| {
+                    data[t * n_vocab + a]
+                        .partial_cmp(&data[t * n_vocab + b])
+                        .unwrap()
+                        .reverse()
+                });
+            }
+            self.reset_candidate();
+            let Self {
+                options,
+                constraint,
+                current_candidates,
+                current_best_score,
+                hypothesis,
+                hotwords,
+                lm,
+                ..
+            } = self;
+            let mut add = |state| {
+                add_candidate(
+                    current_candidates,
+                    current_best_score,
+                    options.beam_threshold,
+                    state,
+                )
+            };
+            for (prev_hyp_idx, prev_hyp) in hypothesis[self.steps].iter().enumerate() {
+                let prev_token = prev_hyp.token;
+                let prev_lm_state = &prev_hyp.lm_state;
+                for &target in target_index.iter().take(options.beam_size_token) {
+                    let token = target as i32;
+                    let am_score = data[t * n_vocab + target];
+                    let score = prev_hyp.score + am_score + options.frame_offset(token, blank_id);
+
+                    if token != blank_id && (token != prev_token || prev_hyp.prev_blank) {
+                        // New token, which the LM scores only if the constraint allows it.
+                        let mut scored = None;
+                        constraint.next(prev_hyp.constraint_state, token, |constraint_state| {
+                            let (lm_state, lm_score, lm_contribution, hotword_node, bonus) = scored
+                                .get_or_insert_with(|| {
+                                    let is_unknown = lm.is_unknown(prev_lm_state, token);
+                                    let (lm_state, lm_score) =
+                                        lm.score(prev_lm_state, token, n_vocab);
+                                    let (hotword_node, hotword_bonus) =
+                                        hotwords.advance(prev_hyp.hotword_node, token);
+                                    (
+                                        lm_state,
+                                        lm_score,
+                                        options.lm_contribution(lm_score, is_unknown),
+                                        hotword_node,
+                                        options.insertion_bonus(token) + hotword_bonus,
+                                    )
+                                });
+                            add(DecoderState {
+                                score: score + *lm_contribution + *bonus,
+                                token,
+                                prev_blank: false,
+                                am_score,
+                                lm_score: *lm_score,
+                                parent_index: prev_hyp_idx as isize,
+                                lm_state: lm_state.clone(),
+                                bonus: *bonus,
+                                hotword_node: *hotword_node,
+                                constraint_state,
+                            });
+                        });
+                    } else {
+                        // Blank, or extend the last token.
+                        add(DecoderState {
+                            score,
+                            token,
+                            prev_blank: token == blank_id,
+                            am_score,
+                            lm_score: prev_hyp.lm_score,
+                            parent_index: prev_hyp_idx as isize,
+                            lm_state: prev_lm_state.clone(),
+                            bonus: prev_hyp.bonus,
+                            hotword_node: prev_hyp.hotword_node,
+                            constraint_state: prev_hyp.constraint_state,
+                        });
+                    }
+                }
+            }
+            // Finalize candidates.
+            self.finalize_candidate(self.steps);
+            self.steps += 1;
+        }
+    }
+
+    /// Scores the end of the sentence, and returns all the hypotheses that the constraint allows to end, sorted by their scores.
+    pub(crate) fn finish(&mut self) -> Vec<DecoderOutput> {
+        self.reset_candidate();
+        if !self.is_started() {
+            return Vec::new();
+        }
+        for (prev_hyp_idx, prev_hyp) in self.hypothesis[self.steps].iter().enumerate() {
+            if !self.constraint.is_final(prev_hyp.constraint_state) {
+                continue;
+            }
+            let (lm_state, lm_score, lm_contribution) =
+                self.options
+                    .lm_finish(&mut self.lm, &prev_hyp.lm_state, self.n_vocab);
+            let state = DecoderState {
+                score: prev_hyp.score
+                    + lm_contribution
+                    + self.hotwords.finish(prev_hyp.hotword_node),
+                token: self.blank_id,
+                prev_blank: false,
+                am_score: prev_hyp.am_score,
+                lm_score: prev_hyp.lm_score + lm_score,
+                parent_index: prev_hyp_idx as isize,
+                lm_state,
+                bonus: prev_hyp.bonus,
+                hotword_node: prev_hyp.hotword_node,
+                constraint_state: prev_hyp.constraint_state,
+            };
+            add_candidate(
+                &mut self.current_candidates,
+                &mut self.current_best_score,
+                self.options.beam_threshold,
+                state,
+            );
+        }
+        if self.current_candidates.is_empty() {
+            // No hypothesis can end here.
+            return Vec::new();
+        }
+        self.finalize_candidate(self.steps);
+        let mut outputs = self.hypothesis[self.steps + 1]
+            .iter()
+            .map(|hyp| self.get_hypothesis(hyp, self.steps + 1))
+            .collect::<Vec<_>>();
+        outputs.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse());
+        outputs
+    }
+
+    /// Returns the best hypothesis of the frames decoded so far, which is empty before `begin`.
+    pub(crate) fn best(&self) -> DecoderOutput {
+        if !self.is_started() {
+            return DecoderOutput::new();
+        }
+        match self.hypothesis[self.steps]
+            .iter()
+            .max_by_key(|hyp| OrderedFloat(hyp.score))
+        {
+            Some(hyp) => self.get_hypothesis(hyp, self.steps),
+            None => DecoderOutput::new(),
+        }
+    }
+
+    /// Returns the output that all the surviving hypotheses share, which is empty before `begin`.
+    pub(crate) fn stable_prefix(&self) -> DecoderOutput {
+        match self.common_ancestor() {
+            Some((step, index)) => self.get_hypothesis(&self.hypothesis[step][index], step),
+            None => DecoderOutput::new(),
+        }
+    }
+
+    /// Drops the history of the time steps up to the stable prefix.
+    pub(crate) fn prune_stable(&mut self) {
+        let (step, index) = match self.common_ancestor() {
+            Some((step, index)) if step > 0 => (step, index),
+            _ => return,
+        };
+        self.committed = self.get_hypothesis(&self.hypothesis[step][index], step);
+        self.hypothesis.drain(0..step);
+        // The common ancestor becomes the new root.
+        self.hypothesis[0][index].parent_index = -1;
+        self.steps -= step;
+        self.pruned_steps += step;
+    }
+
+    fn reset_candidate(&mut self) {
+        self.current_best_score = f32::MIN;
+        self.current_candidates.clear();
+        self.current_candidate_pointers.clear();
+    }
+
+    /// Finalize candidates at the current time step.
+    /// This prunes the candidates, sort them by score and store them into hyp[t + 1].
+    /// If no candidate is proposed (e.g. no token of the frame continues a word), the hypotheses of hyp[t] are kept as they are.
+    fn finalize_candidate(&mut self, t: usize) {
+        if self.current_candidates.is_empty() {
+            let (head, tail) = self.hypothesis.split_at_mut(t + 1);
+            tail[0].clear();
+            for (i, hyp) in head[t].iter().enumerate() {
+                tail[0].push(DecoderState {
+                    parent_index: i as isize,
+                    ..hyp.clone()
+                });
+            }
+            return;
+        }
+
+        // 1. Gather valid candidates.
+        // ================================================================
+        for (i, candidate) in self.current_candidates.iter().enumerate() {
+            if candidate.score > self.current_best_score - self.options.beam_threshold {
+                self.current_candidate_pointers.push(i);
+            }
+        }
+
+        // 2. Merge same patterns.
+        // ================================================================
+        // Sort candidates so that the same patterns are consecutive.
+        self.current_candidate_pointers.sort_by_key(|a| {
+            let x = &self.current_candidates[*a];
+            (
+                &x.lm_state,
+                x.hotword_node,
+                x.constraint_state,
+                x.token,
+                x.prev_blank,
+                OrderedFloat(x.score),
+            )
+        });
+        let mut n_candidates_after_merged = 1;
+        let mut last_ptr = self.current_candidate_pointers[0];
+        for i in 1..self.current_candidate_pointers.len() {
+            let ptr = self.current_candidate_pointers[i];
+            if !self.current_candidates[ptr].is_same_state(&self.current_candidates[last_ptr]) {
+                // Distinct pattern.
+                self.current_candidate_pointers[n_candidates_after_merged] = ptr;
+                n_candidates_after_merged += 1;
+                last_ptr = ptr;
+            } else {
+                // Same pattern.
+                let score = self.current_candidates[ptr].score;
+                let last = &mut self.current_candidates[last_ptr];
+                last.score = log_add(last.score, score);
+            }
+        }
+        self.current_candidate_pointers
+            .truncate(n_candidates_after_merged);
+
+        // 3. Sort candidates.
+        if self.current_candidate_pointers.len() > self.options.beam_size {
+            pdqselect::select_by_key(
+                &mut self.current_candidate_pointers,
+                self.options.beam_size,
+                |&x| OrderedFloat(-self.current_candidates[x].score),
+            );
+        }
+
+        // 4. Copy candidates to output.
+        let output = &mut self.hypothesis[t + 1];
+        output.clear();
+        for &ptr in self
+            .current_candidate_pointers
+            .iter()
+            .take(self.options.beam_size)
+        {
+            output.push(self.current_candidates[ptr].clone());
+        }
+    }
+
+    /// Returns true if `begin` has been called.
+    fn is_started(&self) -> bool {
+        !self.hypothesis.is_empty()
+    }
+
+    /// Returns the time step and the index of the latest hypothesis that all the current hypotheses descend from.
+    /// Returns `None` if there is no hypothesis, i.e. before `begin`.
+    fn common_ancestor(&self) -> Option<(usize, usize)> {
+        if !self.is_started() {
+            return None;
+        }
+        let mut step = self.steps;
+        let mut indices = (0..self.hypothesis[step].len()).collect::<Vec<_>>();
+        loop {
+            indices.sort_unstable();
+            indices.dedup();
+            if indices.len() <= 1 || step == 0 {
+                return indices.first().map(|&index| (step, index));
+            }
+            for index in indices.iter_mut() {
+                *index = self.hypothesis[step][*index].parent_index as usize;
+            }
+            step -= 1;
+        }
+    }
+
+    /// Traces back the hypothesis at the given time step, and returns it as DecoderOutput.
+    fn get_hypothesis(&self, hyp: &DecoderState<T::State, C::State>, step: usize) -> DecoderOutput {
+        let mut output = self.committed.clone();
+        output.score = hyp.score;
+        let mut hyps = Vec::with_capacity(step);
+        let mut hyp_ = hyp;
+        for i in (0..step).rev() {
+            if hyp_.parent_index == -1 {
+                break;
+            }
+            hyps.push(hyp_.clone());
+            hyp_ = &self.hypothesis[i][hyp_.parent_index as usize];
+        }
+        // The root is the blank at the beginning, or the last hypothesis pruned by `prune_stable`.
+        let mut last_token = hyp_.token;
+        for (step, hyp) in hyps.into_iter().rev().enumerate() {
+            let token = hyp.token;
+            if last_token != token && token != self.blank_id {
+                output.tokens.push(token);
+                output.timesteps.push(self.pruned_steps + step);
+                output.end_timesteps.push(self.pruned_steps + step + 1);
+                output.am_scores.push(hyp.am_score);
+                output.confidences.push(hyp.am_score.exp());
+                output.lm_scores.push(hyp.lm_score);
+                if let Some(model_scores) = self.lm.model_scores(&hyp.lm_state) {
+                    output.lm_model_scores.push(model_scores);
+                }
+                output.bonus_scores.push(hyp.bonus);
+            } else if token != self.blank_id {
+                output.repeat_last_token(self.pruned_steps + step + 1, hyp.am_score);
+            }
+            last_token = token;
+        }
+        output
+    }
+}
+
+fn add_candidate<T, C>(
+    output: &mut Vec<DecoderState<T, C>>,
+    current_best_score: &mut f32,
+    beam_threshold: f32,
+    state: DecoderState<T, C>,
+) {
+    if state.score > *current_best_score {
+        *current_best_score = state.score;
+    }
+    if state.score > *current_best_score - beam_threshold {
+        output.push(state);
+    }
+}
@@ -19,8 +19,12 @@ impl Decoder for GreedyDecoder {
             if last_token != token && token != blank_id {
                 output.tokens.push(token);
                 output.timesteps.push(step);
+                output.end_timesteps.push(step + 1);
                 output.am_scores.push(score);
+                output.confidences.push(score.exp());
                 output.score += score;
+            } else if token != blank_id {
+                output.repeat_last_token(step + 1, score);
             }
             last_token = token;
         }
@@ -0,0 +1,145 @@
+use super::{
+    frame_sync::{Constraint, FrameSyncSearch},
+    BeamSearchDecoderOptions, Decoder, DecoderOutput, OptionsError,
+};
+use crate::{hotwords::Hotwords, lexicon::Lexicon, lm::LM};
+
+/// The state of a hypothesis is the position in the lexicon trie of the word being spelled.
+impl Constraint for Lexicon {
+    type State = usize;
+
+    fn start(&self) -> Self::State {
+        Lexicon::ROOT
+    }
+
+    fn next(&self, state: Self::State, token: i32, mut f: impl FnMut(Self::State)) {
+        // Tokens that do not continue any word are never proposed.
+        if let Some(node) = self.child(state, token) {
+            if self.is_word_end(node) {
+                // The word is complete, so the next token starts a new word.
+                f(Lexicon::ROOT);
+            }
+            if self.has_children(node) {
+                f(node);
+            }
+        }
+    }
+
+    /// Hypotheses in the middle of a word are dropped at the end.
+    fn is_final(&self, state: Self::State) -> bool {
+        state == Lexicon::ROOT
+    }
+}
+
+/// LexiconBeamSearchDecoder is a beam search decoder that only emits token sequences spelling words in a lexicon.
+/// Each hypothesis tracks its position in the lexicon trie, and tokens that do not continue any word are never proposed.
+/// No hypothesis is returned if none completes its last word at the end.
+pub struct LexiconBeamSearchDecoder<T: LM> {
+    search: FrameSyncSearch<T, Lexicon>,
+}
+
+impl<T: LM> Decoder for LexiconBeamSearchDecoder<T> {
+    fn decode(
+        &mut self,
+        data: &[f32],
+        steps: usize,
+        tokens: usize,
+        blank_id: i32,
+    ) -> Vec<DecoderOutput> {
+        self.search.begin(blank_id);
+        self.search.step(data, steps, tokens);
+        self.search.finish()
+    }
+}
+
+impl<T: LM> LexiconBeamSearchDecoder<T> {
+    /// Fails if `options` and `lm` set different word separators.
+    pub fn new(
+        options: BeamSearchDecoderOptions,
+        lexicon: Lexicon,
+        lm: T,
+    ) -> Result<Self, OptionsError> {
+        Ok(Self {
+            search: FrameSyncSearch::new(options, lexicon, lm)?,
+        })
+    }
+
+    /// Boosts the phrases of `hotwords` in the hypotheses.
+    pub fn with_hotwords(mut self, hotwords: Hotwords) -> Self {
+        self.search.set_hotwords(hotwords);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        lm::ZeroLM, BeamSearchDecoderOptions, Decoder, Dict, Lexicon, LexiconBeamSearchDecoder,
+    };
+
+    #[test]
+    fn it_emits_only_lexicon_words() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 10,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let dict = Dict::from_entries(["A", "B", "|"].iter().map(|s| s.to_string())).unwrap();
+        let lexicon = Lexicon::parse("AB A B |\n".as_bytes(), &dict).unwrap();
+        let mut decoder = LexiconBeamSearchDecoder::new(options, lexicon, ZeroLM).unwrap();
+        let steps = 3;
+        let tokens = 4;
+        // The best path without the lexicon is "B A |".
+        #[rustfmt::skip]
+        let data = &[
+            0.0, 1.0, 0.0, -5.0,
+            2.0, 0.0, 0.0, -5.0,
+            0.0, 0.0, 1.0, -5.0,
+        ];
+        let outputs = decoder.decode(data, steps, tokens, 3);
+        assert_eq!(outputs[0].tokens, vec![0, 1, 2]);
+        assert_eq!(outputs[0].score, 1.0);
+    }
+
+    #[test]
+    fn it_skips_frames_without_lexicon_tokens() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 10,
+            beam_size_token: 1,
+            ..Default::default()
+        };
+        let dict = Dict::from_entries(["A", "B", "|"].iter().map(|s| s.to_string())).unwrap();
+        let lexicon = Lexicon::parse("A A |\n".as_bytes(), &dict).unwrap();
+        let mut decoder = LexiconBeamSearchDecoder::new(options, lexicon, ZeroLM).unwrap();
+        // Only B is proposed at the second frame, which continues no word.
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+        ];
+        let outputs = decoder.decode(data, 3, 4, 3);
+        assert_eq!(outputs[0].tokens, vec![0, 2]);
+        assert_eq!(outputs[0].timesteps, vec![0, 2]);
+        assert_eq!(outputs[0].score, 2.0);
+    }
+
+    #[test]
+    fn it_emits_no_partial_words() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 10,
+            beam_size_token: 2,
+            ..Default::default()
+        };
+        let dict = Dict::from_entries(["A", "B", "|"].iter().map(|s| s.to_string())).unwrap();
+        let lexicon = Lexicon::parse("AB A B |\n".as_bytes(), &dict).unwrap();
+        let mut decoder = LexiconBeamSearchDecoder::new(options, lexicon, ZeroLM).unwrap();
+        // Only A and B are proposed, and two frames are too short to spell "A B |".
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.5, 0.0, -5.0,
+            0.5, 1.0, 0.0, -5.0,
+        ];
+        assert!(decoder.decode(data, 2, 4, 3).is_empty());
+    }
+}
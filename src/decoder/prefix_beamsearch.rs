@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use ordered_float::OrderedFloat;
+
+use super::{log_add, BeamSearchDecoderOptions, Decoder, DecoderOutput, OptionsError};
+use crate::{
+    hotwords::Hotwords,
+    lm::{LMStateRef, LM},
+};
+
+/// A node of the prefix tree. Each node represents a distinct label sequence.
+#[derive(Debug)]
+struct Prefix<T> {
+    token: i32,
+    /// Index of the parent prefix. The root has no parent.
+    parent: Option<usize>,
+    /// The time step where the token is emitted for the first time.
+    timestep: usize,
+    /// The time step after the last frame where the paths in the beam emit the token.
+    end_timestep: usize,
+    /// The time step after the last frame where the prefix or one of its descendants survives pruning.
+    alive_until: usize,
+    am_score: f32,
+    /// Language model score of the token.
+    lm_score: f32,
+    /// Insertion and hotword bonuses of the token.
+    bonus: f32,
+    /// Weighted language model score and bonuses of the whole label sequence, where unknown tokens are scored by `unk_score`.
+    total_score: f32,
+    lm_state: LMStateRef<T>,
+    /// The position in the hotword automaton.
+    hotword_node: usize,
+}
+
+/// A label sequence with the log probabilities of the paths ending with blank and with non-blank.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Beam {
+    prefix: usize,
+    blank_score: f32,
+    non_blank_score: f32,
+}
+
+impl Beam {
+    /// The log probability of the label sequence.
+    fn am_score(&self) -> f32 {
+        log_add(self.blank_score, self.non_blank_score)
+    }
+}
+
+/// PrefixBeamSearchDecoder is an implementation of the CTC prefix beam search.
+///
+/// Unlike `BeamSearchDecoder`, which keeps the best paths of each frame, this decoder keeps the probabilities of the paths ending with blank and with non-blank for each label sequence.
+/// So the score of a hypothesis is the probability of the label sequence summed over all the paths, and all hypotheses have distinct label sequences.
+pub struct PrefixBeamSearchDecoder<T: LM> {
+    options: BeamSearchDecoderOptions,
+    /// All the label sequences that have been proposed.
+    prefixes: Vec<Prefix<T::State>>,
+    /// Index of the child prefixes, keyed by the parent prefix and the token.
+    children: HashMap<(usize, i32), usize>,
+    beams: Vec<Beam>,
+    next_beams: Vec<Beam>,
+    /// Index of next_beams, keyed by the prefix.
+    next_beam_index: HashMap<usize, usize>,
+    hotwords: Hotwords,
+    /// The language model.
+    lm: T,
+}
+
+impl<T: LM> Decoder for PrefixBeamSearchDecoder<T> {
+    fn decode(
+        &mut self,
+        data: &[f32],
+        steps: usize,
+        tokens: usize,
+        blank_id: i32,
+    ) -> Vec<DecoderOutput> {
+        self.decode_begin(blank_id);
+        self.decode_step(data, steps, tokens, blank_id);
+        let mut outputs = self.decode_end(tokens);
+        for output in outputs.iter_mut() {
+            output.confidences = peak_posteriors(output, data, tokens);
+        }
+        outputs.sort_by(|a, b| a.score.partial_cmp(&b.score).unwrap().reverse());
+        outputs
+    }
+}
+
+impl<T: LM> PrefixBeamSearchDecoder<T> {
+    /// Fails if `options` and `lm` set different word separators.
+    pub fn new(options: BeamSearchDecoderOptions, lm: T) -> Result<Self, OptionsError> {
+        Ok(Self {
+            options: options.with_word_separator_of(&lm)?,
+            prefixes: Vec::new(),
+            children: HashMap::new(),
+            beams: Vec::new(),
+            next_beams: Vec::new(),
+            next_beam_index: HashMap::new(),
+            hotwords: Hotwords::new(),
+            lm,
+        })
+    }
+
+    /// Boosts the phrases of `hotwords` in the hypotheses.
+    pub fn with_hotwords(mut self, mut hotwords: Hotwords) -> Self {
+        hotwords.build();
+        self.hotwords = hotwords;
+        self
+    }
+
+    fn decode_begin(&mut self, blank_id: i32) {
+        self.prefixes.clear();
+        self.children.clear();
+        self.prefixes.push(Prefix {
+            token: blank_id,
+            parent: None,
+            timestep: 0,
+            end_timestep: 0,
+            alive_until: 0,
+            am_score: 0.0,
+            lm_score: 0.0,
+            bonus: 0.0,
+            total_score: 0.0,
+            lm_state: self.lm.start(),
+            hotword_node: Hotwords::ROOT,
+        });
+        self.beams.clear();
+        self.beams.push(Beam {
+            prefix: 0,
+            blank_score: 0.0,
+            non_blank_score: f32::NEG_INFINITY,
+        });
+    }
+
+    fn decode_step(&mut self, data: &[f32], steps: usize, n_vocab: usize, blank_id: i32) {
+        let mut target_index = (0..n_vocab).collect::<Vec<_>>();
+        for t in 0..steps {
+            if n_vocab > self.options.beam_size_token {
+                // Collect tokens with the high score at the top `beam_size_token`.
+                pdqselect::select_by(&mut target_index, self.options.beam_size_token, |&a, &b| {
+                    data[t * n_vocab + a]
+                        .partial_cmp(&data[t * n_vocab + b])
+                        .unwrap()
+                        .reverse()
+                });
+            }
+            self.next_beams.clear();
+            self.next_beam_index.clear();
+            for beam_idx in 0..self.beams.len() {
+                let beam = self.beams[beam_idx];
+                let last_token = self.prefixes[beam.prefix].token;
+                for &target in target_index.iter().take(self.options.beam_size_token) {
+                    let token = target as i32;
+                    let am_score = data[t * n_vocab + target];
+                    let path_score = am_score + self.options.frame_offset(token, blank_id);
+                    if token == blank_id {
+                        // Blank keeps the label sequence.
+                        self.add_beam(beam.prefix, beam.am_score() + path_score, f32::NEG_INFINITY);
+                    } else if token == last_token {
+                        // Repeated tokens are collapsed unless blank is between them.
+                        self.add_beam(
+                            beam.prefix,
+                            f32::NEG_INFINITY,
+                            beam.non_blank_score + path_score,
+                        );
+                        let child = self.extend(beam.prefix, token, t, am_score, n_vocab);
+                        self.add_beam(child, f32::NEG_INFINITY, beam.blank_score + path_score);
+                    } else {
+                        // New token
+                        let child = self.extend(beam.prefix, token, t, am_score, n_vocab);
+                        self.add_beam(child, f32::NEG_INFINITY, beam.am_score() + path_score);
+                    }
+                }
+            }
+            self.prune();
+            for beam in self.beams.iter() {
+                if beam.non_blank_score > f32::NEG_INFINITY {
+                    self.prefixes[beam.prefix].end_timestep = t + 1;
+                }
+                let mut node = Some(beam.prefix);
+                while let Some(idx) = node {
+                    let prefix = &mut self.prefixes[idx];
+                    if prefix.alive_until == t + 1 {
+                        break;
+                    }
+                    prefix.alive_until = t + 1;
+                    node = prefix.parent;
+                }
+            }
+        }
+    }
+
+    fn decode_end(&mut self, n_vocab: usize) -> Vec<DecoderOutput> {
+        let mut outputs = Vec::with_capacity(self.beams.len());
+        for beam in self.beams.iter() {
+            let prefix = &self.prefixes[beam.prefix];
+            let (_, _, lm_contribution) =
+                self.options
+                    .lm_finish(&mut self.lm, &prefix.lm_state, n_vocab);
+            let mut output = DecoderOutput::new();
+            output.score = beam.am_score()
+                + prefix.total_score
+                + lm_contribution
+                + self.hotwords.finish(prefix.hotword_node);
+            let mut node = beam.prefix;
+            let mut next_timestep = usize::MAX;
+            while let Some(parent) = self.prefixes[node].parent {
+                let prefix = &self.prefixes[node];
+                output.tokens.push(prefix.token);
+                output.timesteps.push(prefix.timestep);
+                // Other paths of the prefix may repeat the token after the next token starts.
+                output
+                    .end_timesteps
+                    .push(prefix.end_timestep.min(next_timestep));
+                next_timestep = prefix.timestep;
+                output.am_scores.push(prefix.am_score);
+                output.lm_scores.push(prefix.lm_score);
+                if let Some(model_scores) = self.lm.model_scores(&prefix.lm_state) {
+                    output.lm_model_scores.push(model_scores);
+                }
+                output.bonus_scores.push(prefix.bonus);
+                node = parent;
+            }
+            output.tokens.reverse();
+            output.timesteps.reverse();
+            output.end_timesteps.reverse();
+            output.am_scores.reverse();
+            output.lm_scores.reverse();
+            output.lm_model_scores.reverse();
+            output.bonus_scores.reverse();
+            outputs.push(output);
+        }
+        outputs
+    }
+
+    /// Returns the prefix that appends `token` to `parent`, creating it if this is the first time.
+    /// A prefix that no surviving hypothesis goes through starts again at `timestep`, since none of its earlier paths survive.
+    fn extend(
+        &mut self,
+        parent: usize,
+        token: i32,
+        timestep: usize,
+        am_score: f32,
+        n_vocab: usize,
+    ) -> usize {
+        if let Some(&child) = self.children.get(&(parent, token)) {
+            let prefix = &mut self.prefixes[child];
+            if prefix.alive_until != timestep {
+                prefix.timestep = timestep;
+                prefix.end_timestep = timestep + 1;
+                prefix.am_score = am_score;
+            }
+            return child;
+        }
+        let parent_lm_state = &self.prefixes[parent].lm_state;
+        let is_unknown = self.lm.is_unknown(parent_lm_state, token);
+        let (lm_state, lm_score) = self.lm.score(parent_lm_state, token, n_vocab);
+        let (hotword_node, hotword_bonus) = self
+            .hotwords
+            .advance(self.prefixes[parent].hotword_node, token);
+        let bonus = self.options.insertion_bonus(token) + hotword_bonus;
+        let child = self.prefixes.len();
+        self.prefixes.push(Prefix {
+            token,
+            parent: Some(parent),
+            timestep,
+            end_timestep: timestep + 1,
+            alive_until: timestep,
+            am_score,
+            lm_score,
+            bonus,
+            total_score: self.prefixes[parent].total_score
+                + self.options.lm_contribution(lm_score, is_unknown)
+                + bonus,
+            lm_state,
+            hotword_node,
+        });
+        self.children.insert((parent, token), child);
+        child
+    }
+
+    /// Adds the path probabilities to the beam of the prefix in the next step.
+    fn add_beam(&mut self, prefix: usize, blank_score: f32, non_blank_score: f32) {
+        match self.next_beam_index.get(&prefix) {
+            Some(&idx) => {
+                let beam = &mut self.next_beams[idx];
+                beam.blank_score = log_add(beam.blank_score, blank_score);
+                beam.non_blank_score = log_add(beam.non_blank_score, non_blank_score);
+            }
+            None => {
+                self.next_beam_index.insert(prefix, self.next_beams.len());
+                self.next_beams.push(Beam {
+                    prefix,
+                    blank_score,
+                    non_blank_score,
+                });
+            }
+        }
+    }
+
+    /// Keeps the best `beam_size` beams of the next step, and makes them the current beams.
+    fn prune(&mut self) {
+        let prefixes = &self.prefixes;
+        let score = |beam: &Beam| beam.am_score() + prefixes[beam.prefix].total_score;
+        let best_score = self
+            .next_beams
+            .iter()
+            .map(|beam| OrderedFloat(score(beam)))
+            .max()
+            .map_or(f32::MIN, |x| x.0);
+        self.next_beams
+            .retain(|beam| score(beam) > best_score - self.options.beam_threshold);
+        if self.next_beams.len() > self.options.beam_size {
+            pdqselect::select_by_key(&mut self.next_beams, self.options.beam_size, |beam| {
+                OrderedFloat(-score(beam))
+            });
+            self.next_beams.truncate(self.options.beam_size);
+        }
+        std::mem::swap(&mut self.beams, &mut self.next_beams);
+    }
+}
+
+/// Returns the highest posterior probability of each token of `output` over its frames.
+/// The paths of a prefix are summed, so the frames of each token are read from `data` instead of a single path.
+fn peak_posteriors(output: &DecoderOutput, data: &[f32], n_vocab: usize) -> Vec<f32> {
+    output
+        .tokens
+        .iter()
+        .zip(output.timesteps.iter().zip(output.end_timesteps.iter()))
+        .map(|(&token, (&start, &end))| {
+            (start..end)
+                .map(|t| data[t * n_vocab + token as usize])
+                .fold(f32::NEG_INFINITY, f32::max)
+                .exp()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{lm::ZeroLM, BeamSearchDecoderOptions, Decoder, PrefixBeamSearchDecoder};
+
+    #[test]
+    fn it_sums_paths_of_the_same_label_sequence() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 10,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = PrefixBeamSearchDecoder::new(options, ZeroLM).unwrap();
+        let steps = 2;
+        let tokens = 2;
+        // The best path is blank-blank (0.36), but "A" has the larger probability (0.16 + 0.24 + 0.24).
+        let (a, blank) = (0.4f32.ln(), 0.6f32.ln());
+        let data = &[a, blank, a, blank];
+        let outputs = decoder.decode(data, steps, tokens, 1);
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].tokens, vec![0]);
+        assert_eq!(outputs[0].timesteps, vec![0]);
+        assert!((outputs[0].score - 0.64f32.ln()).abs() < 1e-6);
+        assert_eq!(outputs[1].tokens, Vec::<i32>::new());
+        assert!((outputs[1].score - 0.36f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_restarts_prefixes_that_reenter_the_beam() {
+        let options = BeamSearchDecoderOptions {
+            beam_size: 2,
+            beam_size_token: 10,
+            ..Default::default()
+        };
+        let mut decoder = PrefixBeamSearchDecoder::new(options, ZeroLM).unwrap();
+        // Tokens: 0 = A, 1 = B, 2 = C, 3 = blank.
+        // "A" is in the beam after the first frame, pruned by "B" at the second, and proposed again at the third.
+        #[rustfmt::skip]
+        let probs: &[f32] = &[
+            0.3, 0.2, 0.001, 0.5,
+            0.001, 0.45, 0.05, 0.5,
+            0.9, 0.001, 0.001, 0.1,
+        ];
+        let data = probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+        let outputs = decoder.decode(&data, 3, 4, 3);
+        assert_eq!(outputs[0].tokens, vec![0]);
+        assert_eq!(outputs[0].timesteps, vec![2]);
+        assert_eq!(outputs[0].end_timesteps, vec![3]);
+        assert_eq!(outputs[0].am_scores, vec![0.9f32.ln()]);
+        assert_eq!(outputs[0].confidences, vec![0.9]);
+    }
+}
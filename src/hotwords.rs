@@ -0,0 +1,258 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+use thiserror::Error;
+
+use crate::{dict::DictError, Dict};
+
+#[derive(Error, Debug)]
+pub enum HotwordsError {
+    #[error("invalid line in hotwords: {0}")]
+    InvalidLine(String),
+    #[error("empty hotword phrase")]
+    EmptyPhrase,
+    #[error("unknown token in hotwords")]
+    UnknownToken(#[from] DictError),
+    #[error("failed to load hotwords")]
+    Load(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct HotwordNode {
+    children: HashMap<i32, usize>,
+    /// The node of the longest proper suffix of this node that is also in the trie.
+    fail: usize,
+    /// The bonus of each token of the phrases passing through this node.
+    weight: f32,
+    /// The bonus accumulated from the root to this node.
+    partial: f32,
+    /// The part of `partial` that belongs to complete phrases on the way from the root, which is never retracted.
+    committed: f32,
+    /// The bonus of the complete phrases ending at this node, including the shorter ones found by the failure links.
+    matched: f32,
+    is_end: bool,
+}
+
+impl HotwordNode {
+    /// The bonus given to the phrase being matched, which is retracted if the phrase is abandoned.
+    fn pending(&self) -> f32 {
+        self.partial - self.committed
+    }
+}
+
+/// Hotwords is a list of phrases that decoders boost, e.g. product names and person names that the language model rarely predicts.
+///
+/// A hypothesis gets the weight of a phrase for each token that extends a prefix of the phrase.
+/// The bonus is kept once the phrase is complete, and retracted if the hypothesis abandons the phrase halfway.
+/// Phrases are matched anywhere in a hypothesis with an Aho-Corasick automaton over tokens.
+///
+/// A hotwords file has one phrase per line, with its weight followed by the phrase as whitespace separated `Dict` entries.
+/// For example, `2.0 C T C L I B`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hotwords {
+    nodes: Vec<HotwordNode>,
+    n_phrases: usize,
+    /// False if phrases have been added since the failure links were computed.
+    built: bool,
+}
+
+impl Default for Hotwords {
+    fn default() -> Self {
+        Self {
+            nodes: vec![HotwordNode::default()],
+            n_phrases: 0,
+            built: true,
+        }
+    }
+}
+
+impl Hotwords {
+    /// The node where no phrase is being matched.
+    pub(crate) const ROOT: usize = 0;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<P: AsRef<Path>>(p: P, dict: &Dict) -> Result<Self, HotwordsError> {
+        let file = File::open(p)?;
+        Self::parse(file, dict)
+    }
+
+    pub fn parse(p: impl Read, dict: &Dict) -> Result<Self, HotwordsError> {
+        let mut hotwords = Self::new();
+        let buf = BufReader::new(p);
+        for line in buf.lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let weight = match fields.next() {
+                Some(weight) => weight
+                    .parse::<f32>()
+                    .map_err(|_| HotwordsError::InvalidLine(line.clone()))?,
+                None => continue,
+            };
+            let phrase = fields
+                .map(|entry| dict.index(entry))
+                .collect::<Result<Vec<_>, _>>()?;
+            if phrase.is_empty() {
+                return Err(HotwordsError::InvalidLine(line));
+            }
+            hotwords.add_phrase(&phrase, weight)?;
+        }
+        hotwords.build();
+        Ok(hotwords)
+    }
+
+    /// Adds a phrase spelled by the given token sequence, which gets `weight` for each token.
+    /// A prefix shared by several phrases gets the largest weight of them.
+    pub fn add_phrase(&mut self, phrase: &[i32], weight: f32) -> Result<(), HotwordsError> {
+        if phrase.is_empty() {
+            return Err(HotwordsError::EmptyPhrase);
+        }
+        let mut node = Self::ROOT;
+        for &token in phrase {
+            node = match self.nodes[node].children.get(&token) {
+                Some(&child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(HotwordNode {
+                        weight: f32::NEG_INFINITY,
+                        ..HotwordNode::default()
+                    });
+                    self.nodes[node].children.insert(token, child);
+                    child
+                }
+            };
+            self.nodes[node].weight = self.nodes[node].weight.max(weight);
+        }
+        if !self.nodes[node].is_end {
+            self.nodes[node].is_end = true;
+            self.n_phrases += 1;
+        }
+        self.built = false;
+        Ok(())
+    }
+
+    /// Returns the number of distinct phrases.
+    pub fn len(&self) -> usize {
+        self.n_phrases
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Computes the bonuses and the failure links of the trie in breadth-first order.
+    pub(crate) fn build(&mut self) {
+        if self.built {
+            return;
+        }
+        let mut queue = VecDeque::from([Self::ROOT]);
+        while let Some(node) = queue.pop_front() {
+            let mut children = self.nodes[node]
+                .children
+                .iter()
+                .map(|(&token, &child)| (token, child))
+                .collect::<Vec<_>>();
+            children.sort_unstable();
+            for (token, child) in children {
+                let fail = if node == Self::ROOT {
+                    Self::ROOT
+                } else {
+                    self.next(self.nodes[node].fail, token)
+                };
+                let partial = self.nodes[node].partial + self.nodes[child].weight;
+                let (committed, matched) = if self.nodes[child].is_end {
+                    (partial, partial + self.nodes[fail].matched)
+                } else {
+                    (self.nodes[node].committed, self.nodes[fail].matched)
+                };
+                let child_node = &mut self.nodes[child];
+                child_node.fail = fail;
+                child_node.partial = partial;
+                child_node.committed = committed;
+                child_node.matched = matched;
+                queue.push_back(child);
+            }
+        }
+        self.built = true;
+    }
+
+    /// Returns the node reached by `token` from `node`, following the failure links if no phrase continues that way.
+    fn next(&self, mut node: usize, token: i32) -> usize {
+        loop {
+            if let Some(&child) = self.nodes[node].children.get(&token) {
+                return child;
+            }
+            if node == Self::ROOT {
+                return Self::ROOT;
+            }
+            node = self.nodes[node].fail;
+        }
+    }
+
+    /// Returns the node reached by `token` from `node`, and the bonus of the token.
+    /// The bonus is negative if the token abandons the phrase being matched.
+    pub(crate) fn advance(&self, node: usize, token: i32) -> (usize, f32) {
+        debug_assert!(self.built);
+        let next = self.next(node, token);
+        let bonus =
+            self.nodes[next].pending() - self.nodes[node].pending() + self.nodes[next].matched;
+        (next, bonus)
+    }
+
+    /// Returns the bonus retracted when the hypothesis ends at `node` halfway through a phrase.
+    pub(crate) fn finish(&self, node: usize) -> f32 {
+        -self.nodes[node].pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hotwords;
+    use crate::Dict;
+
+    /// Returns the bonus of each token and the final retraction.
+    fn boost(hotwords: &Hotwords, tokens: &[i32]) -> (Vec<f32>, f32) {
+        let mut node = Hotwords::ROOT;
+        let bonuses = tokens
+            .iter()
+            .map(|&token| {
+                let (next, bonus) = hotwords.advance(node, token);
+                node = next;
+                bonus
+            })
+            .collect();
+        (bonuses, hotwords.finish(node))
+    }
+
+    #[test]
+    fn it_boosts_and_retracts_phrases() {
+        let dict = Dict::from_entries(["A", "B", "C", "D"].iter().map(|s| s.to_string())).unwrap();
+        let hotwords = Hotwords::parse("1.0 A B C\n0.5 B\n".as_bytes(), &dict).unwrap();
+        assert_eq!(hotwords.len(), 2);
+
+        // A B C is complete, and B is found inside it.
+        assert_eq!(
+            boost(&hotwords, &[3, 0, 1, 2, 3]),
+            (vec![0.0, 1.0, 1.5, 1.0, 0.0], 0.0)
+        );
+        // A B is abandoned, but B is kept.
+        assert_eq!(boost(&hotwords, &[0, 1, 3]), (vec![1.0, 1.5, -2.0], 0.0));
+        // A B is retracted at the end.
+        assert_eq!(boost(&hotwords, &[0, 1]), (vec![1.0, 1.5], -2.0));
+        // A A restarts the phrase.
+        assert_eq!(boost(&hotwords, &[0, 0]), (vec![1.0, 0.0], -1.0));
+    }
+
+    #[test]
+    fn it_rejects_invalid_lines() {
+        let dict = Dict::from_entries(["A"].iter().map(|s| s.to_string())).unwrap();
+        assert!(Hotwords::parse("A\n".as_bytes(), &dict).is_err());
+        assert!(Hotwords::parse("1.0\n".as_bytes(), &dict).is_err());
+        assert!(Hotwords::parse("1.0 B\n".as_bytes(), &dict).is_err());
+    }
+}
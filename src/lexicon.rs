@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+use thiserror::Error;
+
+use crate::{dict::DictError, Dict};
+
+#[derive(Error, Debug)]
+pub enum LexiconError {
+    #[error("invalid line in lexicon: {0}")]
+    InvalidLine(String),
+    #[error("empty spelling for word: {0}")]
+    EmptySpelling(String),
+    #[error("unknown token in lexicon")]
+    UnknownToken(#[from] DictError),
+    #[error("failed to load lexicon")]
+    Load(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct LexiconNode {
+    children: HashMap<i32, usize>,
+    /// Indices of the words whose spelling ends at this node.
+    words: Vec<usize>,
+}
+
+/// Lexicon is a set of words and their spellings, stored as a prefix trie over tokens.
+///
+/// A lexicon file has one word per line, followed by its spelling as whitespace separated `Dict` entries.
+/// For example, `THE T H E |`. A word may appear on several lines to have multiple spellings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexicon {
+    nodes: Vec<LexiconNode>,
+    word2idx: HashMap<String, usize>,
+    words: Vec<String>,
+}
+
+impl Default for Lexicon {
+    fn default() -> Self {
+        Self {
+            nodes: vec![LexiconNode::default()],
+            word2idx: HashMap::new(),
+            words: Vec::new(),
+        }
+    }
+}
+
+impl Lexicon {
+    /// The node that represents an empty spelling.
+    pub(crate) const ROOT: usize = 0;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read<P: AsRef<Path>>(p: P, dict: &Dict) -> Result<Self, LexiconError> {
+        let file = File::open(p)?;
+        Self::parse(file, dict)
+    }
+
+    pub fn parse(p: impl Read, dict: &Dict) -> Result<Self, LexiconError> {
+        let mut lexicon = Self::new();
+        let buf = BufReader::new(p);
+        for line in buf.lines() {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let word = match fields.next() {
+                Some(word) => word,
+                None => continue,
+            };
+            let spelling = fields
+                .map(|entry| dict.index(entry))
+                .collect::<Result<Vec<_>, _>>()?;
+            if spelling.is_empty() {
+                return Err(LexiconError::InvalidLine(line));
+            }
+            lexicon.add_word(word.to_owned(), &spelling)?;
+        }
+        Ok(lexicon)
+    }
+
+    /// Adds a word spelled by the given token sequence, and returns the index of the word.
+    pub fn add_word(&mut self, word: String, spelling: &[i32]) -> Result<usize, LexiconError> {
+        if spelling.is_empty() {
+            return Err(LexiconError::EmptySpelling(word));
+        }
+        let mut node = Self::ROOT;
+        for &token in spelling {
+            node = match self.nodes[node].children.get(&token) {
+                Some(&child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(LexiconNode::default());
+                    self.nodes[node].children.insert(token, child);
+                    child
+                }
+            };
+        }
+        let idx = match self.word2idx.get(&word) {
+            Some(&idx) => idx,
+            None => {
+                let idx = self.words.len();
+                self.word2idx.insert(word.clone(), idx);
+                self.words.push(word);
+                idx
+            }
+        };
+        if !self.nodes[node].words.contains(&idx) {
+            self.nodes[node].words.push(idx);
+        }
+        Ok(idx)
+    }
+
+    /// Returns the number of distinct words.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn word(&self, idx: usize) -> Option<&str> {
+        self.words.get(idx).map(|w| w.as_str())
+    }
+
+    /// Returns the node reached by spelling `token` from `node`, if any word continues that way.
+    pub(crate) fn child(&self, node: usize, token: i32) -> Option<usize> {
+        self.nodes[node].children.get(&token).copied()
+    }
+
+    /// Returns true if some word continues past `node`.
+    pub(crate) fn has_children(&self, node: usize) -> bool {
+        !self.nodes[node].children.is_empty()
+    }
+
+    /// Returns true if the spelling of some word ends at `node`.
+    pub(crate) fn is_word_end(&self, node: usize) -> bool {
+        !self.nodes[node].words.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lexicon;
+    use crate::Dict;
+
+    #[test]
+    fn parse_lexicon() {
+        let dict = Dict::from_entries(["A", "N", "|"].iter().map(|s| s.to_string())).unwrap();
+        let lexicon = Lexicon::parse("A A |\nAN A N |\nAN A N\n".as_bytes(), &dict).unwrap();
+        assert_eq!(lexicon.len(), 2);
+        assert_eq!(lexicon.word(1), Some("AN"));
+
+        let a = lexicon.child(Lexicon::ROOT, 0).unwrap();
+        assert!(!lexicon.is_word_end(a));
+        let an = lexicon.child(a, 1).unwrap();
+        assert!(lexicon.is_word_end(an));
+        assert!(lexicon.has_children(an));
+        let an_sep = lexicon.child(an, 2).unwrap();
+        assert!(lexicon.is_word_end(an_sep));
+        assert!(!lexicon.has_children(an_sep));
+        assert_eq!(lexicon.child(Lexicon::ROOT, 1), None);
+    }
+}
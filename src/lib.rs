@@ -1,11 +1,27 @@
+mod aligner;
 mod decoder;
 mod dict;
+mod hotwords;
+mod lexicon;
 mod lm;
+mod loss;
 
+pub use aligner::{forced_align, AlignError, Alignment};
 pub use decoder::{
-    BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput, GreedyDecoder,
+    BatchDecoder, BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput,
+    GreedyDecoder, InputError, InputKind, LexiconBeamSearchDecoder, OptionsError,
+    PrefixBeamSearchDecoder, WordSpan,
 };
 pub use dict::Dict;
+pub use hotwords::{Hotwords, HotwordsError};
+pub use lexicon::{Lexicon, LexiconError};
+pub use lm::arpa::{ArpaLM, ArpaModel};
+pub use lm::binary::{BinaryNgramLM, BinaryNgramModel};
+pub use lm::estimate::NgramEstimator;
+pub use lm::interpolate::{InterpolatedLM, Interpolation};
 #[cfg(feature = "kenlm")]
-pub use lm::kenlm::KenLM;
-pub use lm::{LMStateRef, ZeroLM, LM};
+pub use lm::kenlm::{ArpaLoadComplain, KenLM, KenLMConfig, KenLMModel, LoadMethod};
+pub use lm::multi::MultiLM;
+pub use lm::ngram::{NgramLM, NgramModel, NgramWeights};
+pub use lm::{LMError, LMStateRef, ZeroLM, LM};
+pub use loss::{ctc_loss, ctc_loss_and_grad};
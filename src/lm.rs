@@ -1,23 +1,83 @@
+pub mod arpa;
+pub mod binary;
+pub mod estimate;
+pub mod interpolate;
 #[cfg(feature = "kenlm")]
 pub mod kenlm;
+pub mod multi;
+pub mod ngram;
+mod word_level;
 
 use std::{
-    cell::{Ref, RefCell},
     fmt::Debug,
-    rc::Rc,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
 };
+use thiserror::Error;
+
+use crate::Dict;
+
+#[derive(Error, Debug)]
+pub enum LMError {
+    #[error("language model file not found: {0}")]
+    NotFound(PathBuf),
+    #[error("invalid language model path: {0:?}")]
+    InvalidPath(String),
+    #[error("failed to parse language model: {0}")]
+    Parse(String),
+    #[error("vocabulary mismatch: {0}")]
+    VocabularyMismatch(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("dictionary entries out of the vocabulary of the language model: {0:?}")]
+    OutOfVocabulary(Vec<String>),
+    #[error("failed to read or write language model")]
+    Io(#[from] std::io::Error),
+}
+
+/// Returns the entries of `dict` indexed by their indices, which language models map to their own vocabularies.
+pub(crate) fn dict_entries(dict: &Dict) -> Result<Vec<String>, LMError> {
+    let mut entries = vec![String::new(); dict.len()];
+    for (word, &idx) in dict.iter() {
+        if idx < 0 || idx as usize >= dict.len() {
+            return Err(LMError::VocabularyMismatch(format!(
+                "index {} of {:?} is out of the dictionary size {}",
+                idx,
+                word,
+                dict.len()
+            )));
+        }
+        entries[idx as usize] = word.clone();
+    }
+    Ok(entries)
+}
+
+pub(crate) fn check_word_separator(dict: &Dict, word_separator: i32) -> Result<(), LMError> {
+    if word_separator < 0 || word_separator as usize >= dict.len() {
+        return Err(LMError::VocabularyMismatch(format!(
+            "word separator {} is out of the dictionary size {}",
+            word_separator,
+            dict.len()
+        )));
+    }
+    Ok(())
+}
 
 #[derive(Debug, Default)]
 pub struct LMState<T> {
-    children: Vec<Option<LMStateRef<T>>>,
+    // Each slot is initialized at most once, so looking up a child does not take a lock.
+    children: OnceLock<Box<[OnceLock<LMStateRef<T>>]>>,
+    /// The state after the end of the sentence.
+    eos: OnceLock<LMStateRef<T>>,
     #[allow(dead_code)]
     state: T,
 }
 
 /// A reference to a LM state.
 /// LMStateRef holds the information of the token sequence being decoded, and the identity of the token sequence can be confirmed by comparing LMStateRef.
+/// LMStateRef is `Send` and `Sync` as long as the internal state is, so decoders can be moved across threads.
 #[derive(Default)]
-pub struct LMStateRef<T>(Rc<RefCell<LMState<T>>>);
+pub struct LMStateRef<T>(Arc<LMState<T>>);
 
 impl<T> Clone for LMStateRef<T> {
     fn clone(&self) -> Self {
@@ -27,52 +87,50 @@ impl<T> Clone for LMStateRef<T> {
 
 impl<T> std::hash::Hash for LMStateRef<T> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.as_ptr().hash(state)
+        Arc::as_ptr(&self.0).hash(state)
     }
 }
 
 impl<T> LMStateRef<T> {
     pub fn new(state: T) -> Self {
-        Self(Rc::new(RefCell::new(LMState {
-            children: Vec::new(),
+        Self(Arc::new(LMState {
+            children: OnceLock::new(),
+            eos: OnceLock::new(),
             state,
-        })))
+        }))
     }
 
     pub fn child(&self, token: i32, n_vocab: usize, state: T) -> Self {
-        let mut self_state = self.0.borrow_mut();
         // Allocate spaces lazily.
-        if self_state.children.is_empty() {
-            self_state.children.resize(n_vocab + 1 /* EOS */, None);
-        }
-        let child = &mut self_state.children[token as usize];
-        match child {
-            // If the child is already allocated, return it.
-            Some(ref child) => child.clone(),
-            // If not, allocate it and return it.
-            None => {
-                let new_child = LMStateRef::new(state);
-                *child = Some(new_child.clone());
-                new_child
-            }
-        }
+        let children = self
+            .0
+            .children
+            .get_or_init(|| (0..n_vocab).map(|_| OnceLock::new()).collect());
+        // If the child is already allocated, return it. If not, allocate it and return it.
+        children[token as usize]
+            .get_or_init(|| LMStateRef::new(state))
+            .clone()
+    }
+
+    /// Returns the state after the end of the sentence, which `LM::finish` returns.
+    pub fn eos_child(&self, state: T) -> Self {
+        self.0.eos.get_or_init(|| LMStateRef::new(state)).clone()
     }
 
-    pub fn borrow_internal_state(&self) -> Ref<'_, T> {
-        let r = self.0.borrow();
-        Ref::map(r, |s| &s.state)
+    pub fn borrow_internal_state(&self) -> &T {
+        &self.0.state
     }
 }
 
 impl<T> Debug for LMStateRef<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:p}", self.0.as_ptr())
+        write!(f, "{:p}", Arc::as_ptr(&self.0))
     }
 }
 
 impl<T> PartialEq for LMStateRef<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.0.as_ptr() == other.0.as_ptr()
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 impl<T> Eq for LMStateRef<T> {}
@@ -85,7 +143,7 @@ impl<T> PartialOrd for LMStateRef<T> {
 
 impl<T> Ord for LMStateRef<T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0.as_ptr().cmp(&other.0.as_ptr())
+        Arc::as_ptr(&self.0).cmp(&Arc::as_ptr(&other.0))
     }
 }
 
@@ -103,6 +161,20 @@ pub trait LM {
     ) -> (LMStateRef<Self::State>, f32);
     // Returns the new state and the score of the final state.
     fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32);
+    /// Returns true if the LM scores `token` following `state` as its unknown symbol (e.g. `<unk>` of KenLM).
+    /// Decoders replace the LM score of such tokens with `BeamSearchDecoderOptions::unk_score` if it is set.
+    fn is_unknown(&mut self, _state: &LMStateRef<Self::State>, _token: i32) -> bool {
+        false
+    }
+    /// Returns the token that ends a word if the LM scores words spelled by tokens, e.g. `ArpaLM::new_word_level`.
+    fn word_separator(&self) -> Option<i32> {
+        None
+    }
+    /// Returns the score of each fused model for the token that leads to `state`, if the LM fuses several models like `MultiLM`.
+    /// Decoders record them in `DecoderOutput::lm_model_scores`.
+    fn model_scores(&self, _state: &LMStateRef<Self::State>) -> Option<Vec<f32>> {
+        None
+    }
 }
 
 /// ZeroLM is a language model that always returns 0.
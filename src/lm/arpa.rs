@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::Dict;
+
+use super::{
+    ngram::{NgramLM, NgramModel, NgramWeights, WordIndex, UNKNOWN_MISSING_LOGPROB},
+    LMError,
+};
+
+/// A backoff n-gram model read from an ARPA file.
+/// Probabilities are log10, the same as KenLM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArpaModel {
+    words: Vec<String>,
+    word2idx: HashMap<String, WordIndex>,
+    /// n-grams of each order, keyed by the word indices. `ngrams[0]` has unigrams.
+    ngrams: Vec<HashMap<Vec<WordIndex>, NgramWeights>>,
+    unk: WordIndex,
+    begin_sentence: WordIndex,
+    end_sentence: WordIndex,
+}
+
+impl ArpaModel {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, LMError> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(LMError::NotFound(path.into()));
+        }
+        let file = File::open(path)?;
+        Self::parse(file)
+    }
+
+    pub fn parse(p: impl Read) -> Result<Self, LMError> {
+        let mut words = Vec::new();
+        let mut word2idx = HashMap::new();
+        let mut counts = Vec::new();
+        let mut ngrams: Vec<HashMap<Vec<WordIndex>, NgramWeights>> = Vec::new();
+        // The order of the section being read. 0 means the header.
+        let mut order = 0;
+        let mut in_data = false;
+        let mut ended = false;
+        for (lineno, line) in BufReader::new(p).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            let error = |message: &str| LMError::Parse(format!("line {}: {}", lineno + 1, message));
+            if line.is_empty() || ended {
+                continue;
+            }
+            if line == "\\data\\" {
+                in_data = true;
+                continue;
+            }
+            if line == "\\end\\" {
+                ended = true;
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('\\') {
+                let n = section
+                    .strip_suffix("-grams:")
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .ok_or_else(|| error("unknown section"))?;
+                if n != order + 1 || n > counts.len() {
+                    return Err(error("unexpected n-gram section"));
+                }
+                order = n;
+                ngrams.push(HashMap::with_capacity(counts[n - 1]));
+                continue;
+            }
+            if order == 0 {
+                if !in_data {
+                    continue;
+                }
+                let count = line
+                    .strip_prefix("ngram ")
+                    .and_then(|count| count.split_once('='))
+                    .filter(|(n, _)| n.trim().parse::<usize>() == Ok(counts.len() + 1))
+                    .and_then(|(_, count)| count.trim().parse::<usize>().ok())
+                    .ok_or_else(|| error("invalid n-gram count"))?;
+                counts.push(count);
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let prob = fields
+                .next()
+                .and_then(|prob| prob.parse::<f32>().ok())
+                .ok_or_else(|| error("invalid probability"))?;
+            let mut key = Vec::with_capacity(order);
+            for _ in 0..order {
+                let word = fields.next().ok_or_else(|| error("too few words"))?;
+                let idx = match word2idx.get(word) {
+                    Some(&idx) => idx,
+                    None if order == 1 => {
+                        let idx = words.len() as WordIndex;
+                        word2idx.insert(word.to_owned(), idx);
+                        words.push(word.to_owned());
+                        idx
+                    }
+                    None => return Err(error("word missing from unigrams")),
+                };
+                key.push(idx);
+            }
+            let backoff = match fields.next() {
+                Some(backoff) => backoff
+                    .parse::<f32>()
+                    .map_err(|_| error("invalid backoff"))?,
+                None => 0.0,
+            };
+            if fields.next().is_some() {
+                return Err(error("too many fields"));
+            }
+            ngrams[order - 1].insert(
+                key,
+                NgramWeights {
+                    prob,
+                    backoff,
+                    has_extension: false,
+                },
+            );
+        }
+        if ngrams.is_empty() || ngrams.len() != counts.len() {
+            return Err(LMError::Parse("missing n-gram sections".to_string()));
+        }
+        for (n, (table, &count)) in ngrams.iter().zip(counts.iter()).enumerate() {
+            if table.len() != count {
+                return Err(LMError::Parse(format!(
+                    "expected {} {}-grams, but found {}",
+                    count,
+                    n + 1,
+                    table.len()
+                )));
+            }
+        }
+        for n in 1..ngrams.len() {
+            let (shorter, longer) = ngrams.split_at_mut(n);
+            for key in longer[0].keys() {
+                if let Some(weights) = shorter[n - 1].get_mut(&key[..n]) {
+                    weights.has_extension = true;
+                }
+            }
+        }
+        let index = |word: &str| {
+            word2idx
+                .get(word)
+                .copied()
+                .ok_or_else(|| LMError::Parse(format!("{} is missing from unigrams", word)))
+        };
+        let begin_sentence = index("<s>")?;
+        let end_sentence = index("</s>")?;
+        let unk = match word2idx.get("<unk>") {
+            Some(&unk) => unk,
+            None => {
+                let unk = words.len() as WordIndex;
+                word2idx.insert("<unk>".to_string(), unk);
+                words.push("<unk>".to_string());
+                ngrams[0].insert(
+                    vec![unk],
+                    NgramWeights {
+                        prob: UNKNOWN_MISSING_LOGPROB,
+                        backoff: 0.0,
+                        has_extension: false,
+                    },
+                );
+                unk
+            }
+        };
+        Ok(Self {
+            words,
+            word2idx,
+            ngrams,
+            unk,
+            begin_sentence,
+            end_sentence,
+        })
+    }
+
+    /// Returns the words of the model indexed by `WordIndex`.
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    /// Returns the n-grams of each order. `ngram_tables()[0]` has unigrams.
+    pub(super) fn ngram_tables(&self) -> &[HashMap<Vec<WordIndex>, NgramWeights>] {
+        &self.ngrams
+    }
+}
+
+impl NgramModel for ArpaModel {
+    fn order(&self) -> usize {
+        self.ngrams.len()
+    }
+
+    fn index(&self, word: &str) -> WordIndex {
+        self.word2idx.get(word).copied().unwrap_or(self.unk)
+    }
+
+    fn unk(&self) -> WordIndex {
+        self.unk
+    }
+
+    fn begin_sentence(&self) -> WordIndex {
+        self.begin_sentence
+    }
+
+    fn end_sentence(&self) -> WordIndex {
+        self.end_sentence
+    }
+
+    fn weights(&self, ngram: &[WordIndex]) -> Option<NgramWeights> {
+        self.ngrams[ngram.len() - 1].get(ngram).copied()
+    }
+}
+
+/// A pure Rust n-gram language model read from an ARPA file.
+/// This is available without the `kenlm` feature.
+pub type ArpaLM = NgramLM<ArpaModel>;
+
+impl NgramLM<ArpaModel> {
+    pub fn new<P: AsRef<Path>>(path: P, dict: &Dict) -> Result<Self, LMError> {
+        Self::from_model(Arc::new(ArpaModel::read(path)?), dict)
+    }
+
+    /// Creates ArpaLM in word-level mode, which is described in `NgramLM`.
+    pub fn new_word_level<P: AsRef<Path>>(
+        path: P,
+        dict: &Dict,
+        word_separator: i32,
+    ) -> Result<Self, LMError> {
+        Self::from_model_word_level(Arc::new(ArpaModel::read(path)?), dict, word_separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArpaModel;
+    use crate::lm::{ngram::NgramModel, LMError};
+
+    const ARPA: &str = "
+\\data\\
+ngram 1=4
+ngram 2=2
+
+\\1-grams:
+-1.0\t<s>\t-0.5
+-0.5\t</s>
+-0.25\tA\t-0.125
+-2.0\t<unk>
+
+\\2-grams:
+-0.1\t<s> A
+-0.2\tA </s>
+
+\\end\\
+";
+
+    #[test]
+    fn it_scores_with_backoff() {
+        let model = ArpaModel::parse(ARPA.as_bytes()).unwrap();
+        assert_eq!(model.order(), 2);
+        let bos = model.begin_sentence();
+        let a = model.index("A");
+        let (context, score) = model.score(&[bos], a);
+        assert_eq!(score, -0.1);
+        assert_eq!(context, vec![a]);
+        let (_, score) = model.score(&context, model.end_sentence());
+        assert_eq!(score, -0.2);
+        // A A is not a bigram, so it backs off to the unigram A.
+        let (_, score) = model.score(&context, a);
+        assert_eq!(score, -0.125 + -0.25);
+        // <unk> is never a context.
+        let (context, score) = model.score(&[bos], model.index("B"));
+        assert_eq!(score, -0.5 + -2.0);
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_malformed_files() {
+        assert!(ArpaModel::parse("A\nB\n".as_bytes()).is_err());
+        let truncated = ARPA.replace("-0.2\tA </s>\n", "");
+        assert!(ArpaModel::parse(truncated.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn it_reports_read_failures_as_io_errors() {
+        struct FailingReader;
+        impl std::io::Read for FailingReader {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disconnected"))
+            }
+        }
+        assert!(matches!(
+            ArpaModel::parse(FailingReader),
+            Err(LMError::Io(_))
+        ));
+    }
+}
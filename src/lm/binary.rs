@@ -0,0 +1,610 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::Dict;
+
+use super::{
+    arpa::ArpaModel,
+    ngram::{NgramLM, NgramModel, NgramWeights, WordIndex},
+    LMError,
+};
+
+const MAGIC: &[u8; 8] = b"CTCLIBNG";
+const VERSION: u32 = 1;
+/// The number of centers of a quantization codebook. A quantized weight is stored as a byte.
+const QUANTIZATION_CENTERS: usize = 256;
+
+/// The bytes of a binary model, either read into memory or mapped from the file.
+enum Storage {
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Storage::Owned(bytes) => bytes,
+            #[cfg(feature = "mmap")]
+            Storage::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// How the weights of an order are stored.
+#[derive(Debug, Clone, Copy)]
+enum WeightsLayout {
+    /// `[f32; count]` of probabilities and backoffs.
+    Raw { probs: usize, backoffs: usize },
+    /// Codebooks of `[f32; QUANTIZATION_CENTERS]` and `[u8; count]` of codes.
+    Quantized {
+        prob_centers: usize,
+        backoff_centers: usize,
+        probs: usize,
+        backoffs: usize,
+    },
+}
+
+/// Offsets of the arrays of an order in the file.
+#[derive(Debug, Clone, Copy)]
+struct OrderLayout {
+    count: usize,
+    /// `[u32; count * n]` of the word indices of n-grams sorted lexicographically. Unigrams do not have it since their keys are their indices.
+    keys: usize,
+    weights: WeightsLayout,
+    /// `[u8; count]` which is 1 if the n-gram has an extension.
+    extensions: usize,
+}
+
+/// A backoff n-gram model in the binary format of ctclib.
+///
+/// The file consists of sorted arrays, so the model is used without parsing, and it is mapped into memory when the `mmap` feature is enabled.
+/// Processes that load the same file share its pages.
+/// Use `ArpaModel::write_binary` or `BinaryNgramModel::convert_arpa` to make a binary file from an ARPA file.
+pub struct BinaryNgramModel {
+    data: Storage,
+    order: usize,
+    unk: WordIndex,
+    begin_sentence: WordIndex,
+    end_sentence: WordIndex,
+    n_words: usize,
+    /// `[u64; n_words + 1]` of offsets of words in `word_bytes`.
+    word_offsets: usize,
+    word_bytes: usize,
+    /// `[u32; n_words]` of word indices sorted by their spellings.
+    sorted_words: usize,
+    orders: Vec<OrderLayout>,
+}
+
+impl BinaryNgramModel {
+    /// Loads a binary model. The file is mapped into memory if the `mmap` feature is enabled.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LMError> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Err(LMError::NotFound(path.into()));
+        }
+        #[cfg(feature = "mmap")]
+        {
+            let file = File::open(path)?;
+            // The file must not be modified while it is mapped.
+            let mmap = unsafe { memmap2::Mmap::map(&file)? };
+            Self::from_storage(Storage::Mapped(mmap))
+        }
+        #[cfg(not(feature = "mmap"))]
+        {
+            Self::from_bytes(std::fs::read(path)?)
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, LMError> {
+        Self::from_storage(Storage::Owned(bytes))
+    }
+
+    /// Converts an ARPA file into a binary file.
+    /// If `quantize` is true, the weights of bigrams and higher are quantized into 8 bits.
+    pub fn convert_arpa<P: AsRef<Path>, Q: AsRef<Path>>(
+        arpa: P,
+        binary: Q,
+        quantize: bool,
+    ) -> Result<(), LMError> {
+        let model = ArpaModel::read(arpa)?;
+        let mut writer = BufWriter::new(File::create(binary)?);
+        model.write_binary(&mut writer, quantize)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn from_storage(data: Storage) -> Result<Self, LMError> {
+        let mut reader = HeaderReader {
+            data: &data,
+            pos: 0,
+        };
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(LMError::Parse("not a binary n-gram model".to_string()));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(LMError::Parse(format!(
+                "unsupported binary n-gram model version {}",
+                version
+            )));
+        }
+        let order = reader.u32()? as usize;
+        let unk = reader.u32()?;
+        let begin_sentence = reader.u32()?;
+        let end_sentence = reader.u32()?;
+        let quantized = reader.u32()? != 0;
+        let n_words = reader.u64()? as usize;
+        let counts = (0..order)
+            .map(|_| reader.u64().map(|count| count as usize))
+            .collect::<Result<Vec<_>, _>>()?;
+        if order == 0 || counts[0] != n_words {
+            return Err(LMError::Parse("invalid binary n-gram model".to_string()));
+        }
+
+        let word_offsets = reader.section(array_len(8, &[n_words.saturating_add(1)])?)?;
+        let word_bytes_len = read_u64(&data, word_offsets + 8 * n_words) as usize;
+        let word_bytes = reader.section(word_bytes_len)?;
+        let sorted_words = reader.section(array_len(4, &[n_words])?)?;
+        let mut orders = Vec::with_capacity(order);
+        for (i, &count) in counts.iter().enumerate() {
+            let n = i + 1;
+            let keys = if n == 1 {
+                0
+            } else {
+                reader.section(array_len(4, &[count, n])?)?
+            };
+            let weights = if quantized && n > 1 {
+                WeightsLayout::Quantized {
+                    prob_centers: reader.section(4 * QUANTIZATION_CENTERS)?,
+                    backoff_centers: reader.section(4 * QUANTIZATION_CENTERS)?,
+                    probs: reader.section(count)?,
+                    backoffs: reader.section(count)?,
+                }
+            } else {
+                WeightsLayout::Raw {
+                    probs: reader.section(array_len(4, &[count])?)?,
+                    backoffs: reader.section(array_len(4, &[count])?)?,
+                }
+            };
+            let extensions = reader.section(count)?;
+            orders.push(OrderLayout {
+                count,
+                keys,
+                weights,
+                extensions,
+            });
+        }
+        // The last offset is the length of `word_bytes`, so non-decreasing offsets are all in it.
+        let mut last_offset = 0;
+        for i in 0..=n_words {
+            let offset = read_u64(&data, word_offsets + 8 * i) as usize;
+            if offset < last_offset {
+                return Err(LMError::Parse("invalid word offsets".to_string()));
+            }
+            last_offset = offset;
+        }
+        if (0..n_words).any(|i| read_u32(&data, sorted_words + 4 * i) as usize >= n_words) {
+            return Err(LMError::Parse("invalid sorted word index".to_string()));
+        }
+        if [unk, begin_sentence, end_sentence]
+            .iter()
+            .any(|&idx| idx as usize >= n_words)
+        {
+            return Err(LMError::Parse("invalid special word index".to_string()));
+        }
+        Ok(Self {
+            data,
+            order,
+            unk,
+            begin_sentence,
+            end_sentence,
+            n_words,
+            word_offsets,
+            word_bytes,
+            sorted_words,
+            orders,
+        })
+    }
+
+    /// Returns the number of words in the vocabulary.
+    pub fn n_words(&self) -> usize {
+        self.n_words
+    }
+
+    /// Returns the word of `idx`.
+    pub fn word(&self, idx: WordIndex) -> Option<&str> {
+        if idx as usize >= self.n_words {
+            return None;
+        }
+        std::str::from_utf8(self.word_bytes(idx)).ok()
+    }
+
+    fn word_bytes(&self, idx: WordIndex) -> &[u8] {
+        let begin = read_u64(&self.data, self.word_offsets + 8 * idx as usize) as usize;
+        let end = read_u64(&self.data, self.word_offsets + 8 * (idx as usize + 1)) as usize;
+        &self.data[self.word_bytes + begin..self.word_bytes + end]
+    }
+
+    fn key_cmp(&self, layout: &OrderLayout, i: usize, ngram: &[WordIndex]) -> Ordering {
+        let n = ngram.len();
+        for (j, &word) in ngram.iter().enumerate() {
+            let key = read_u32(&self.data, layout.keys + 4 * (i * n + j));
+            match key.cmp(&word) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// Returns the position of `ngram` in the arrays of its order.
+    fn find(&self, ngram: &[WordIndex]) -> Option<usize> {
+        let layout = self.orders.get(ngram.len() - 1)?;
+        if ngram.len() == 1 {
+            return Some(ngram[0] as usize).filter(|&i| i < layout.count);
+        }
+        let (mut lo, mut hi) = (0, layout.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.key_cmp(layout, mid, ngram) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+}
+
+impl NgramModel for BinaryNgramModel {
+    fn order(&self) -> usize {
+        self.order
+    }
+
+    fn index(&self, word: &str) -> WordIndex {
+        let (mut lo, mut hi) = (0, self.n_words);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let idx = read_u32(&self.data, self.sorted_words + 4 * mid);
+            match self.word_bytes(idx).cmp(word.as_bytes()) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return idx,
+            }
+        }
+        self.unk
+    }
+
+    fn unk(&self) -> WordIndex {
+        self.unk
+    }
+
+    fn begin_sentence(&self) -> WordIndex {
+        self.begin_sentence
+    }
+
+    fn end_sentence(&self) -> WordIndex {
+        self.end_sentence
+    }
+
+    fn weights(&self, ngram: &[WordIndex]) -> Option<NgramWeights> {
+        let i = self.find(ngram)?;
+        let layout = &self.orders[ngram.len() - 1];
+        let (prob, backoff) = match layout.weights {
+            WeightsLayout::Raw { probs, backoffs } => (
+                read_f32(&self.data, probs + 4 * i),
+                read_f32(&self.data, backoffs + 4 * i),
+            ),
+            WeightsLayout::Quantized {
+                prob_centers,
+                backoff_centers,
+                probs,
+                backoffs,
+            } => (
+                read_f32(&self.data, prob_centers + 4 * self.data[probs + i] as usize),
+                read_f32(
+                    &self.data,
+                    backoff_centers + 4 * self.data[backoffs + i] as usize,
+                ),
+            ),
+        };
+        Some(NgramWeights {
+            prob,
+            backoff,
+            has_extension: self.data[layout.extensions + i] != 0,
+        })
+    }
+}
+
+/// A pure Rust n-gram language model read from a binary file made by `BinaryNgramModel::convert_arpa`.
+pub type BinaryNgramLM = NgramLM<BinaryNgramModel>;
+
+impl NgramLM<BinaryNgramModel> {
+    pub fn new<P: AsRef<Path>>(path: P, dict: &Dict) -> Result<Self, LMError> {
+        Self::from_model(Arc::new(BinaryNgramModel::load(path)?), dict)
+    }
+
+    /// Creates BinaryNgramLM in word-level mode, which is described in `NgramLM`.
+    pub fn new_word_level<P: AsRef<Path>>(
+        path: P,
+        dict: &Dict,
+        word_separator: i32,
+    ) -> Result<Self, LMError> {
+        Self::from_model_word_level(
+            Arc::new(BinaryNgramModel::load(path)?),
+            dict,
+            word_separator,
+        )
+    }
+}
+
+impl ArpaModel {
+    /// Writes the model in the binary format of `BinaryNgramModel`.
+    /// If `quantize` is true, the weights of bigrams and higher are quantized into 8 bits.
+    pub fn write_binary<W: Write>(&self, w: &mut W, quantize: bool) -> std::io::Result<()> {
+        let mut writer = SectionWriter { w, pos: 0 };
+        let tables = self.ngram_tables();
+        let words = self.words();
+
+        writer.write(MAGIC)?;
+        writer.write(&VERSION.to_le_bytes())?;
+        writer.write(&(tables.len() as u32).to_le_bytes())?;
+        for idx in [self.unk(), self.begin_sentence(), self.end_sentence()] {
+            writer.write(&idx.to_le_bytes())?;
+        }
+        writer.write(&(quantize as u32).to_le_bytes())?;
+        writer.write(&(words.len() as u64).to_le_bytes())?;
+        for table in tables {
+            writer.write(&(table.len() as u64).to_le_bytes())?;
+        }
+        writer.pad()?;
+
+        let mut offset = 0u64;
+        writer.write(&offset.to_le_bytes())?;
+        for word in words {
+            offset += word.len() as u64;
+            writer.write(&offset.to_le_bytes())?;
+        }
+        writer.pad()?;
+        for word in words {
+            writer.write(word.as_bytes())?;
+        }
+        writer.pad()?;
+        let mut sorted_words = (0..words.len() as WordIndex).collect::<Vec<_>>();
+        sorted_words.sort_by(|&a, &b| words[a as usize].cmp(&words[b as usize]));
+        for idx in sorted_words {
+            writer.write(&idx.to_le_bytes())?;
+        }
+        writer.pad()?;
+
+        for (i, table) in tables.iter().enumerate() {
+            let mut ngrams = table.iter().collect::<Vec<_>>();
+            ngrams.sort_by(|a, b| a.0.cmp(b.0));
+            if i > 0 {
+                for (key, _) in ngrams.iter() {
+                    for idx in key.iter() {
+                        writer.write(&idx.to_le_bytes())?;
+                    }
+                }
+                writer.pad()?;
+            }
+            let probs = ngrams.iter().map(|(_, w)| w.prob).collect::<Vec<_>>();
+            let backoffs = ngrams.iter().map(|(_, w)| w.backoff).collect::<Vec<_>>();
+            if quantize && i > 0 {
+                let prob_codebook = Codebook::new(&probs);
+                let backoff_codebook = Codebook::new(&backoffs);
+                writer.write_f32s(&prob_codebook.centers)?;
+                writer.write_f32s(&backoff_codebook.centers)?;
+                let prob_codes = probs.iter().map(|&x| prob_codebook.encode(x));
+                writer.write(&prob_codes.collect::<Vec<_>>())?;
+                writer.pad()?;
+                let backoff_codes = backoffs.iter().map(|&x| backoff_codebook.encode(x));
+                writer.write(&backoff_codes.collect::<Vec<_>>())?;
+                writer.pad()?;
+            } else {
+                writer.write_f32s(&probs)?;
+                writer.write_f32s(&backoffs)?;
+            }
+            let extensions = ngrams.iter().map(|(_, w)| w.has_extension as u8);
+            writer.write(&extensions.collect::<Vec<_>>())?;
+            writer.pad()?;
+        }
+        Ok(())
+    }
+}
+
+/// A codebook to quantize values into bytes.
+/// Values are split into bins of the same size, and each bin is represented by the mean of its values.
+/// If there are not more distinct values than the centers, each value is represented exactly.
+struct Codebook {
+    centers: Vec<f32>,
+    /// The largest value of each bin.
+    bounds: Vec<f32>,
+}
+
+impl Codebook {
+    fn new(values: &[f32]) -> Self {
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f32::total_cmp);
+        let mut distinct = sorted.clone();
+        distinct.dedup();
+        let (mut centers, bounds) = if distinct.len() <= QUANTIZATION_CENTERS {
+            (distinct.clone(), distinct)
+        } else {
+            let bin_size = sorted.len().div_ceil(QUANTIZATION_CENTERS);
+            sorted
+                .chunks(bin_size)
+                .map(|bin| {
+                    let mean = bin.iter().map(|&x| x as f64).sum::<f64>() / bin.len() as f64;
+                    (mean as f32, bin[bin.len() - 1])
+                })
+                .unzip()
+        };
+        centers.resize(QUANTIZATION_CENTERS, 0.0);
+        Self { centers, bounds }
+    }
+
+    fn encode(&self, value: f32) -> u8 {
+        let code = self.bounds.partition_point(|&bound| bound < value);
+        code.min(self.bounds.len() - 1) as u8
+    }
+}
+
+/// Writes sections aligned to 8 bytes.
+struct SectionWriter<'a, W> {
+    w: &'a mut W,
+    pos: usize,
+}
+
+impl<'a, W: Write> SectionWriter<'a, W> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.pos += bytes.len();
+        self.w.write_all(bytes)
+    }
+
+    fn write_f32s(&mut self, values: &[f32]) -> std::io::Result<()> {
+        for value in values {
+            self.write(&value.to_le_bytes())?;
+        }
+        self.pad()
+    }
+
+    fn pad(&mut self) -> std::io::Result<()> {
+        let padding = (8 - self.pos % 8) % 8;
+        self.write(&[0; 8][..padding])
+    }
+}
+
+/// Reads the header, and returns the offsets of sections aligned to 8 bytes.
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], LMError> {
+        let end = match self.pos.checked_add(len) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return Err(LMError::Parse("truncated binary n-gram model".to_string())),
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, LMError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, LMError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Skips a section of `len` bytes and the padding after it, and returns the offset of the section.
+    fn section(&mut self, len: usize) -> Result<usize, LMError> {
+        self.pos = self.pos.div_ceil(8) * 8;
+        let offset = self.pos;
+        self.bytes(len)?;
+        self.pos = self.pos.div_ceil(8) * 8;
+        Ok(offset)
+    }
+}
+
+/// Returns the byte length of an array of `dims` elements of `size` bytes, failing if it overflows.
+fn array_len(size: usize, dims: &[usize]) -> Result<usize, LMError> {
+    dims.iter()
+        .try_fold(size, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| LMError::Parse("too large binary n-gram model".to_string()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_f32(data: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinaryNgramModel, Codebook};
+    use crate::{lm::ngram::NgramModel, ArpaModel, LMError};
+
+    #[test]
+    fn it_reads_what_is_written() {
+        let arpa = ArpaModel::read("data/overfit.arpa").unwrap();
+        for quantize in [false, true] {
+            let mut bytes = Vec::new();
+            arpa.write_binary(&mut bytes, quantize).unwrap();
+            let binary = BinaryNgramModel::from_bytes(bytes).unwrap();
+            assert_eq!(binary.order(), arpa.order());
+            assert_eq!(binary.n_words(), arpa.words().len());
+            for (idx, word) in arpa.words().iter().enumerate() {
+                assert_eq!(binary.index(word), idx as u32);
+                assert_eq!(binary.word(idx as u32), Some(word.as_str()));
+            }
+            assert_eq!(binary.index("NOT A WORD"), arpa.unk());
+            for table in arpa.ngram_tables() {
+                for (ngram, weights) in table {
+                    // The model has less distinct weights than the centers, so they are exact even if quantized.
+                    assert_eq!(binary.weights(ngram), Some(*weights));
+                }
+            }
+            assert_eq!(binary.weights(&[1, 1, 1]), None);
+        }
+    }
+
+    #[test]
+    fn it_quantizes_to_the_nearest_bin() {
+        let values = (0..1024).map(|x| x as f32).collect::<Vec<_>>();
+        let codebook = Codebook::new(&values);
+        assert_eq!(codebook.encode(0.0), 0);
+        assert_eq!(codebook.centers[0], 1.5);
+        assert_eq!(codebook.encode(1023.0), 255);
+        assert_eq!(codebook.centers[255], 1021.5);
+    }
+
+    #[test]
+    fn it_rejects_broken_files() {
+        assert!(BinaryNgramModel::from_bytes(b"CTCLIBNG".to_vec()).is_err());
+        assert!(BinaryNgramModel::from_bytes(b"not a model".to_vec()).is_err());
+
+        let mut bytes = Vec::new();
+        let arpa = ArpaModel::read("data/overfit.arpa").unwrap();
+        arpa.write_binary(&mut bytes, false).unwrap();
+        let binary = BinaryNgramModel::from_bytes(bytes.clone()).unwrap();
+        let mut broken = bytes.clone();
+        let sorted_words = binary.sorted_words;
+        broken[sorted_words..sorted_words + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            BinaryNgramModel::from_bytes(broken),
+            Err(LMError::Parse(_))
+        ));
+        let mut broken = bytes;
+        // The number of words, which overflows the length of the word offsets.
+        broken[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            BinaryNgramModel::from_bytes(broken),
+            Err(LMError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn it_quantizes_nan() {
+        let codebook = Codebook::new(&[1.0, f32::NAN, -1.0]);
+        assert_eq!(codebook.encode(-1.0), 0);
+        assert_eq!(codebook.encode(1.0), 1);
+    }
+}
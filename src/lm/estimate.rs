@@ -0,0 +1,326 @@
+use std::{collections::HashMap, io::Write};
+
+use crate::Dict;
+
+use super::LMError;
+
+const UNK: u32 = 0;
+const BOS: u32 = 1;
+const EOS: u32 = 2;
+
+/// The discounts used when the counts of counts are too sparse to estimate them, the same as `lmplz --discount_fallback`.
+const DISCOUNT_FALLBACK: [f64; 3] = [0.5, 1.0, 1.5];
+
+/// Statistics of the n-grams that share a context.
+#[derive(Debug, Clone, Copy, Default)]
+struct ContextStats {
+    total: u64,
+    /// The number of n-grams whose counts are 1, 2, and 3 or more.
+    counts_of_counts: [u64; 3],
+}
+
+/// NgramEstimator trains an interpolated modified Kneser-Ney n-gram model from sentences, as KenLM's `lmplz` does.
+///
+/// Sentences are sequences of `Dict` tokens, and each token is a word of the model.
+/// The model is written as an ARPA file, which `ArpaLM` and `KenLM` can read.
+pub struct NgramEstimator {
+    order: usize,
+    entries: Vec<String>,
+    words: Vec<String>,
+    /// The word of each token. Tokens are added to `words` when they appear for the first time.
+    token_to_word: HashMap<i32, u32>,
+    /// Raw counts of the n-grams of each order. `counts[0]` has unigrams.
+    counts: Vec<HashMap<Vec<u32>, u64>>,
+}
+
+impl NgramEstimator {
+    pub fn new(order: usize, dict: &Dict) -> Result<Self, LMError> {
+        if order == 0 {
+            return Err(LMError::InvalidArgument(
+                "the order must be positive".to_string(),
+            ));
+        }
+        Ok(Self {
+            order,
+            entries: super::dict_entries(dict)?,
+            words: vec!["<unk>".to_string(), "<s>".to_string(), "</s>".to_string()],
+            token_to_word: HashMap::new(),
+            counts: vec![HashMap::new(); order],
+        })
+    }
+
+    /// Counts the n-grams of a sentence. `<s>` and `</s>` are added around the sentence.
+    pub fn add_sentence(&mut self, tokens: &[i32]) -> Result<(), LMError> {
+        let mut sentence = Vec::with_capacity(tokens.len() + 2);
+        sentence.push(BOS);
+        for &token in tokens {
+            sentence.push(self.word(token)?);
+        }
+        sentence.push(EOS);
+        for end in 1..=sentence.len() {
+            for n in 1..=self.order.min(end) {
+                *self.counts[n - 1]
+                    .entry(sentence[end - n..end].to_vec())
+                    .or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, token: i32) -> Result<u32, LMError> {
+        if let Some(&word) = self.token_to_word.get(&token) {
+            return Ok(word);
+        }
+        let entry = usize::try_from(token)
+            .ok()
+            .and_then(|idx| self.entries.get(idx))
+            .ok_or_else(|| {
+                LMError::VocabularyMismatch(format!("token {} is not in the dictionary", token))
+            })?;
+        let word = match entry.as_str() {
+            "<unk>" => UNK,
+            "" | "<s>" | "</s>" => {
+                return Err(LMError::VocabularyMismatch(format!(
+                    "token {} ({:?}) cannot be a word",
+                    token, entry
+                )))
+            }
+            entry if entry.contains(char::is_whitespace) => {
+                return Err(LMError::VocabularyMismatch(format!(
+                    "token {} ({:?}) cannot be a word",
+                    token, entry
+                )))
+            }
+            entry => {
+                self.words.push(entry.to_string());
+                (self.words.len() - 1) as u32
+            }
+        };
+        self.token_to_word.insert(token, word);
+        Ok(word)
+    }
+
+    /// Returns the counts used to estimate each order.
+    /// The highest order uses raw counts, and the lower orders use the numbers of distinct words preceding the n-grams.
+    /// The n-grams starting with `<s>` use raw counts since nothing precedes `<s>`.
+    fn adjusted_counts(&self) -> Vec<HashMap<Vec<u32>, u64>> {
+        let mut adjusted = vec![HashMap::new(); self.order];
+        adjusted[self.order - 1] = self.counts[self.order - 1].clone();
+        for n in 1..self.order {
+            for (ngram, &count) in self.counts[n - 1].iter() {
+                if ngram[0] == BOS {
+                    adjusted[n - 1].insert(ngram.clone(), count);
+                }
+            }
+            for ngram in self.counts[n].keys() {
+                *adjusted[n - 1].entry(ngram[1..].to_vec()).or_insert(0) += 1;
+            }
+        }
+        // <s> is never predicted.
+        adjusted[0].remove(&vec![BOS]);
+        adjusted
+    }
+
+    /// Estimates the discounts of an order from the counts of counts.
+    fn discounts(counts: &HashMap<Vec<u32>, u64>) -> [f64; 3] {
+        let mut t = [0u64; 4];
+        for &count in counts.values() {
+            if (1..=4).contains(&count) {
+                t[count as usize - 1] += 1;
+            }
+        }
+        if t.contains(&0) {
+            return DISCOUNT_FALLBACK;
+        }
+        let t = t.map(|t| t as f64);
+        let y = t[0] / (t[0] + 2.0 * t[1]);
+        let discounts = [
+            1.0 - 2.0 * y * t[1] / t[0],
+            2.0 - 3.0 * y * t[2] / t[1],
+            3.0 - 4.0 * y * t[3] / t[2],
+        ];
+        if discounts
+            .iter()
+            .enumerate()
+            .all(|(i, &d)| d > 0.0 && d < (i + 1) as f64)
+        {
+            discounts
+        } else {
+            DISCOUNT_FALLBACK
+        }
+    }
+
+    /// Estimates the model, and returns the log10 probabilities and backoffs of the n-grams of each order.
+    fn estimate(&self) -> Vec<Vec<(Vec<u32>, f32, f32)>> {
+        let adjusted = self.adjusted_counts();
+        let mut probs: Vec<HashMap<Vec<u32>, f64>> = vec![HashMap::new(); self.order];
+        let mut gammas: Vec<HashMap<Vec<u32>, f64>> = vec![HashMap::new(); self.order];
+        for n in 1..=self.order {
+            let discounts = Self::discounts(&adjusted[n - 1]);
+            let discount = |count: u64| discounts[count.min(3) as usize - 1];
+            let mut stats: HashMap<&[u32], ContextStats> = HashMap::new();
+            for (ngram, &count) in adjusted[n - 1].iter() {
+                let stats = stats.entry(&ngram[..n - 1]).or_default();
+                stats.total += count;
+                stats.counts_of_counts[count.min(3) as usize - 1] += 1;
+            }
+            for (&context, stats) in stats.iter() {
+                let gamma = (0..3)
+                    .map(|i| discounts[i] * stats.counts_of_counts[i] as f64)
+                    .sum::<f64>()
+                    / stats.total as f64;
+                gammas[n - 1].insert(context.to_vec(), gamma);
+            }
+            if n == 1 {
+                // Unigrams are interpolated with the uniform distribution over the words except <s>.
+                let stats = stats.get(&[][..]).copied().unwrap_or_default();
+                let gamma = gammas[0].get(&vec![]).copied().unwrap_or(1.0);
+                let uniform = 1.0 / (self.words.len() - 1) as f64;
+                for word in (0..self.words.len() as u32).filter(|&word| word != BOS) {
+                    let discounted = match adjusted[0].get(&vec![word]) {
+                        Some(&count) => (count as f64 - discount(count)) / stats.total as f64,
+                        None => 0.0,
+                    };
+                    probs[0].insert(vec![word], discounted + gamma * uniform);
+                }
+                continue;
+            }
+            for (ngram, &count) in adjusted[n - 1].iter() {
+                let context = &ngram[..n - 1];
+                let discounted = (count as f64 - discount(count)) / stats[context].total as f64;
+                let lower = probs[n - 2][&ngram[1..]];
+                let prob = discounted + gammas[n - 1][context] * lower;
+                probs[n - 1].insert(ngram.clone(), prob);
+            }
+        }
+        let mut ngrams = Vec::with_capacity(self.order);
+        for n in 1..=self.order {
+            let mut entries = probs[n - 1]
+                .iter()
+                .map(|(ngram, &prob)| {
+                    let backoff = gammas.get(n).and_then(|gammas| gammas.get(ngram));
+                    let backoff = backoff.map_or(0.0, |gamma| gamma.log10() as f32);
+                    (ngram.clone(), prob.log10() as f32, backoff)
+                })
+                .collect::<Vec<_>>();
+            if n == 1 {
+                let backoff = gammas
+                    .get(1)
+                    .and_then(|gammas| gammas.get(&vec![BOS]))
+                    .map_or(0.0, |gamma| gamma.log10() as f32);
+                // <s> is never predicted, so it gets -99 as in the ARPA files of other toolkits.
+                entries.push((vec![BOS], -99.0, backoff));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            ngrams.push(entries);
+        }
+        ngrams
+    }
+
+    /// Estimates the model, and writes it in the ARPA format.
+    /// The orders without any n-gram (e.g. longer than all the sentences) are left out.
+    pub fn write_arpa<W: Write>(&self, w: &mut W) -> Result<(), LMError> {
+        if self.counts[0].is_empty() {
+            return Err(LMError::InvalidArgument(
+                "no sentence has been added".to_string(),
+            ));
+        }
+        let mut ngrams = self.estimate();
+        // An n-gram has all its prefixes, so only the highest orders can be empty.
+        let order = ngrams
+            .iter()
+            .take_while(|entries| !entries.is_empty())
+            .count();
+        ngrams.truncate(order);
+        writeln!(w, "\\data\\")?;
+        for (n, entries) in ngrams.iter().enumerate() {
+            writeln!(w, "ngram {}={}", n + 1, entries.len())?;
+        }
+        for (n, entries) in ngrams.iter().enumerate() {
+            writeln!(w, "\n\\{}-grams:", n + 1)?;
+            for (ngram, prob, backoff) in entries {
+                let words = ngram
+                    .iter()
+                    .map(|&word| self.words[word as usize].as_str())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if n + 1 < order {
+                    writeln!(w, "{}\t{}\t{}", prob, words, backoff)?;
+                } else {
+                    writeln!(w, "{}\t{}", prob, words)?;
+                }
+            }
+        }
+        writeln!(w, "\n\\end\\")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NgramEstimator;
+    use crate::{lm::ngram::NgramModel, ArpaModel, Dict, LMError};
+
+    #[test]
+    fn it_estimates_normalized_distributions() {
+        let dict = Dict::from_entries(["A", "B", "C"].iter().map(|s| s.to_string())).unwrap();
+        let mut estimator = NgramEstimator::new(3, &dict).unwrap();
+        for sentence in [&[0, 1, 2][..], &[0, 1], &[1, 1, 0], &[2], &[0, 2, 1, 0]] {
+            estimator.add_sentence(sentence).unwrap();
+        }
+        let mut arpa = Vec::new();
+        estimator.write_arpa(&mut arpa).unwrap();
+        let model = ArpaModel::parse(arpa.as_slice()).unwrap();
+        assert_eq!(model.order(), 3);
+
+        let words = ["A", "B", "C", "</s>", "<unk>"].map(|word| model.index(word));
+        let (a, b) = (words[0], words[1]);
+        let bos = model.begin_sentence();
+        // Seen, partially seen, and unseen contexts.
+        for context in [vec![bos], vec![bos, a], vec![a, b], vec![b, b], vec![]] {
+            let total = words
+                .iter()
+                .map(|&word| 10f64.powf(model.score(&context, word).1 as f64))
+                .sum::<f64>();
+            assert!((total - 1.0).abs() < 1e-5, "{:?}: {}", context, total);
+        }
+    }
+
+    #[test]
+    fn it_leaves_out_orders_without_ngrams() {
+        let dict = Dict::from_entries(["A"].iter().map(|s| s.to_string())).unwrap();
+        let mut estimator = NgramEstimator::new(5, &dict).unwrap();
+        estimator.add_sentence(&[0]).unwrap();
+        let mut arpa = Vec::new();
+        estimator.write_arpa(&mut arpa).unwrap();
+        let arpa = String::from_utf8(arpa).unwrap();
+        assert!(arpa.contains("ngram 3=1\n"));
+        assert!(!arpa.contains("ngram 4="));
+        assert!(arpa.contains("-99\t<s>\t"));
+        let model = ArpaModel::parse(arpa.as_bytes()).unwrap();
+        assert_eq!(model.order(), 3);
+    }
+
+    #[test]
+    fn it_rejects_tokens_that_cannot_be_words() {
+        let dict = Dict::from_entries(["", "A"].iter().map(|s| s.to_string())).unwrap();
+        let mut estimator = NgramEstimator::new(2, &dict).unwrap();
+        assert!(estimator.add_sentence(&[1]).is_ok());
+        assert!(estimator.add_sentence(&[0]).is_err());
+        assert!(estimator.add_sentence(&[2]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_arguments() {
+        let dict = Dict::from_entries(["A"].iter().map(|s| s.to_string())).unwrap();
+        assert!(matches!(
+            NgramEstimator::new(0, &dict),
+            Err(LMError::InvalidArgument(_))
+        ));
+        let estimator = NgramEstimator::new(2, &dict).unwrap();
+        assert!(matches!(
+            estimator.write_arpa(&mut Vec::new()),
+            Err(LMError::InvalidArgument(_))
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::LMStateRef;
+
+use super::LM;
+
+/// How InterpolatedLM combines the scores of two language models.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    /// The log10 of the weighted sum of probabilities, `log10(w * 10^a + (1 - w) * 10^b)`.
+    /// Scores are log10 probabilities, the same as KenLM.
+    Linear { weight: f32 },
+    /// The weighted sum of scores, `weight_a * a + weight_b * b`.
+    LogLinear { weight_a: f32, weight_b: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterpolatedState<A, B> {
+    a: LMStateRef<A>,
+    b: LMStateRef<B>,
+}
+
+/// InterpolatedLM combines two language models into one, e.g. a general KenLM and a domain LM, without merging their files.
+/// Nest InterpolatedLM to combine more than two models.
+pub struct InterpolatedLM<A, B> {
+    a: A,
+    b: B,
+    interpolation: Interpolation,
+}
+
+impl<A: LM, B: LM> InterpolatedLM<A, B> {
+    /// Creates InterpolatedLM that mixes the probabilities of `a` and `b` with `weight` and `1 - weight`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is not in `[0, 1]`.
+    pub fn linear(a: A, b: B, weight: f32) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&weight),
+            "weight must be in [0, 1], but got {}",
+            weight
+        );
+        Self::new(a, b, Interpolation::Linear { weight })
+    }
+
+    /// Creates InterpolatedLM that adds the scores of `a` and `b` multiplied by their weights.
+    pub fn log_linear(a: A, b: B, weight_a: f32, weight_b: f32) -> Self {
+        Self::new(a, b, Interpolation::LogLinear { weight_a, weight_b })
+    }
+
+    fn new(a: A, b: B, interpolation: Interpolation) -> Self {
+        Self {
+            a,
+            b,
+            interpolation,
+        }
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn models(&self) -> (&A, &B) {
+        (&self.a, &self.b)
+    }
+
+    fn combine(&self, a: f32, b: f32) -> f32 {
+        match self.interpolation {
+            Interpolation::Linear { weight } => {
+                let a = a + weight.log10();
+                let b = b + (1.0 - weight).log10();
+                let max = a.max(b);
+                if max == f32::NEG_INFINITY {
+                    return max;
+                }
+                max + (10f32.powf(a - max) + 10f32.powf(b - max)).log10()
+            }
+            Interpolation::LogLinear { weight_a, weight_b } => weight_a * a + weight_b * b,
+        }
+    }
+}
+
+impl<A: LM, B: LM> LM for InterpolatedLM<A, B> {
+    type State = InterpolatedState<A::State, B::State>;
+
+    fn start(&mut self) -> LMStateRef<Self::State> {
+        LMStateRef::new(InterpolatedState {
+            a: self.a.start(),
+            b: self.b.start(),
+        })
+    }
+
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32) {
+        let InterpolatedState { a, b } = state.borrow_internal_state();
+        let (a, score_a) = self.a.score(a, token, n_vocab);
+        let (b, score_b) = self.b.score(b, token, n_vocab);
+        let score = self.combine(score_a, score_b);
+        (
+            state.child(token, n_vocab, InterpolatedState { a, b }),
+            score,
+        )
+    }
+
+    /// A token is unknown only if both models do not know it, since the other model still gives it a probability.
+    fn is_unknown(&mut self, state: &LMStateRef<Self::State>, token: i32) -> bool {
+        let InterpolatedState { a, b } = state.borrow_internal_state();
+        self.a.is_unknown(a, token) && self.b.is_unknown(b, token)
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        self.a.word_separator().or_else(|| self.b.word_separator())
+    }
+
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+        let InterpolatedState { a, b } = state.borrow_internal_state();
+        let (a, score_a) = self.a.finish(a);
+        let (b, score_b) = self.b.finish(b);
+        let score = self.combine(score_a, score_b);
+        (state.eos_child(InterpolatedState { a, b }), score)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InterpolatedLM;
+    use crate::{LMStateRef, LM};
+
+    /// A language model that scores every token with the same log10 probability, and knows only token 0.
+    struct ConstantLM(f32);
+
+    impl LM for ConstantLM {
+        type State = ();
+
+        fn start(&mut self) -> LMStateRef<Self::State> {
+            LMStateRef::new(())
+        }
+
+        fn score(
+            &mut self,
+            state: &LMStateRef<Self::State>,
+            token: i32,
+            n_vocab: usize,
+        ) -> (LMStateRef<Self::State>, f32) {
+            (state.child(token, n_vocab, ()), self.0)
+        }
+
+        fn is_unknown(&mut self, _state: &LMStateRef<Self::State>, token: i32) -> bool {
+            token != 0
+        }
+
+        fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+            (state.clone(), self.0)
+        }
+    }
+
+    #[test]
+    fn it_interpolates_probabilities() {
+        let mut lm = InterpolatedLM::linear(ConstantLM(-1.0), ConstantLM(-2.0), 0.5);
+        let root = lm.start();
+        let (state, score) = lm.score(&root, 1, 3);
+        assert!((score - 0.055f32.log10()).abs() < 1e-6);
+        assert_eq!(lm.score(&root, 1, 3).0, state);
+        let (_, score) = lm.finish(&state);
+        assert!((score - 0.055f32.log10()).abs() < 1e-6);
+
+        let mut lm = InterpolatedLM::linear(ConstantLM(-1.0), ConstantLM(-2.0), 1.0);
+        let root = lm.start();
+        assert!((lm.score(&root, 1, 3).1 - -1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_combines_scores_log_linearly() {
+        let mut lm = InterpolatedLM::log_linear(ConstantLM(-1.0), ConstantLM(-2.0), 0.5, 2.0);
+        let root = lm.start();
+        assert_eq!(lm.score(&root, 1, 3).1, -4.5);
+    }
+
+    #[test]
+    fn it_reports_tokens_unknown_to_both_models() {
+        let mut lm = InterpolatedLM::linear(ConstantLM(-1.0), ConstantLM(-2.0), 0.5);
+        let root = lm.start();
+        assert!(!lm.is_unknown(&root, 0));
+        assert!(lm.is_unknown(&root, 1));
+    }
+
+    #[test]
+    fn it_finishes_without_scoring_tokens() {
+        let mut lm = InterpolatedLM::log_linear(ConstantLM(-1.0), ConstantLM(-2.0), 1.0, 1.0);
+        let root = lm.start();
+        let (state, score) = lm.finish(&root);
+        assert_eq!(score, -3.0);
+        assert_eq!(lm.finish(&root).0, state);
+        assert_ne!(lm.score(&root, 0, 1).0, state);
+    }
+}
@@ -1,66 +1,288 @@
-use std::ffi::CString;
+use std::{
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_void},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{Dict, LMStateRef};
 
-use super::LM;
+use super::{
+    word_level::{WordLevel, WordModel, WordState},
+    LMError, LM,
+};
 
 pub type KenLMWordIndex = ctclib_kenlm_sys::lm_WordIndex;
 
+/// The state of the KenLM model, which is the context of the next word.
 #[derive(Debug, Clone)]
-pub struct KenLMState(ctclib_kenlm_sys::lm_ngram_State);
+pub struct KenLMContext {
+    state: ctclib_kenlm_sys::lm_ngram_State,
+}
+
+pub type KenLMState = WordState<KenLMContext>;
 
-impl KenLMState {
+impl KenLMContext {
     fn new() -> Self {
-        Self(unsafe { std::mem::zeroed() })
+        Self {
+            state: unsafe { std::mem::zeroed() },
+        }
     }
 
     fn with_ptr<T: 'static>(
         &self,
         f: impl FnOnce(*const ctclib_kenlm_sys::lm_ngram_State) -> T,
     ) -> T {
-        f(&self.0 as *const _)
+        f(&self.state as *const _)
     }
 
     fn with_mut_ptr<T: 'static>(
         &mut self,
         f: impl FnOnce(*mut ctclib_kenlm_sys::lm_ngram_State) -> T,
     ) -> T {
-        let ptr = &mut self.0 as *mut ctclib_kenlm_sys::lm_ngram_State;
+        let ptr = &mut self.state as *mut ctclib_kenlm_sys::lm_ngram_State;
         f(ptr)
     }
 }
 
-/// A wrapper of a KenLM Model.
-struct Model(*mut ctclib_kenlm_sys::lm_base_Model);
+/// How KenLM loads a binary model file.
+/// ARPA files are always parsed into memory, so this only matters for binary (`.klm`) files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadMethod {
+    /// mmap the file without populating it. Pages are read from disk on demand.
+    Lazy,
+    /// mmap the file and populate it if possible, falling back to `Lazy`.
+    PopulateOrLazy,
+    /// mmap the file and populate it if possible, falling back to `Read`.
+    PopulateOrRead,
+    /// Read the whole file into memory.
+    Read,
+    /// Read the whole file into memory with multiple threads.
+    ParallelRead,
+}
+
+impl LoadMethod {
+    fn to_raw(self) -> ctclib_kenlm_sys::util_LoadMethod {
+        match self {
+            LoadMethod::Lazy => ctclib_kenlm_sys::util_LoadMethod_LAZY,
+            LoadMethod::PopulateOrLazy => ctclib_kenlm_sys::util_LoadMethod_POPULATE_OR_LAZY,
+            LoadMethod::PopulateOrRead => ctclib_kenlm_sys::util_LoadMethod_POPULATE_OR_READ,
+            LoadMethod::Read => ctclib_kenlm_sys::util_LoadMethod_READ,
+            LoadMethod::ParallelRead => ctclib_kenlm_sys::util_LoadMethod_PARALLEL_READ,
+        }
+    }
+}
+
+/// When KenLM warns that an ARPA file is slow to load and should be converted to the binary format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpaLoadComplain {
+    All,
+    Expensive,
+    None,
+}
+
+impl ArpaLoadComplain {
+    fn to_raw(self) -> ctclib_kenlm_sys::lm_ngram_Config_ARPALoadComplain {
+        match self {
+            ArpaLoadComplain::All => ctclib_kenlm_sys::lm_ngram_Config_ARPALoadComplain_ALL,
+            ArpaLoadComplain::Expensive => {
+                ctclib_kenlm_sys::lm_ngram_Config_ARPALoadComplain_EXPENSIVE
+            }
+            ArpaLoadComplain::None => ctclib_kenlm_sys::lm_ngram_Config_ARPALoadComplain_NONE,
+        }
+    }
+}
+
+/// Options to load a KenLM model. The defaults are the same as KenLM's.
+///
+/// ```ignore
+/// let config = KenLMConfig::new()
+///     .load_method(LoadMethod::Lazy)
+///     .enumerate_vocab(true);
+/// let model = KenLMModel::load_with_config("model.klm", &config)?;
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KenLMConfig {
+    load_method: LoadMethod,
+    arpa_complain: ArpaLoadComplain,
+    unknown_missing_logprob: f32,
+    enumerate_vocab: bool,
+    write_binary: Option<PathBuf>,
+}
+
+impl Default for KenLMConfig {
+    fn default() -> Self {
+        Self {
+            load_method: LoadMethod::PopulateOrRead,
+            arpa_complain: ArpaLoadComplain::All,
+            unknown_missing_logprob: -100.0,
+            enumerate_vocab: false,
+            write_binary: None,
+        }
+    }
+}
+
+impl KenLMConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load_method(mut self, load_method: LoadMethod) -> Self {
+        self.load_method = load_method;
+        self
+    }
+
+    pub fn arpa_complain(mut self, arpa_complain: ArpaLoadComplain) -> Self {
+        self.arpa_complain = arpa_complain;
+        self
+    }
+
+    /// The log10 probability of `<unk>` if the ARPA file does not have it.
+    pub fn unknown_missing_logprob(mut self, logprob: f32) -> Self {
+        self.unknown_missing_logprob = logprob;
+        self
+    }
+
+    /// Collects the words of the model while loading, which are available by `KenLMModel::vocabulary`.
+    pub fn enumerate_vocab(mut self, enumerate_vocab: bool) -> Self {
+        self.enumerate_vocab = enumerate_vocab;
+        self
+    }
+
+    /// Writes the model in the binary format to `path` while loading an ARPA file, like KenLM's `build_binary`.
+    pub fn write_binary<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.write_binary = Some(path.into());
+        self
+    }
+}
+
+/// Stores a word reported by KenLM into the `Vec<String>` pointed by `context`.
+unsafe extern "C" fn collect_word(
+    context: *mut c_void,
+    index: KenLMWordIndex,
+    str: *const c_char,
+    len: ctclib_kenlm_sys::size_t,
+) {
+    let words = &mut *(context as *mut Vec<String>);
+    let bytes = std::slice::from_raw_parts(str as *const u8, len as usize);
+    let index = index as usize;
+    if words.len() <= index {
+        words.resize(index + 1, String::new());
+    }
+    words[index] = String::from_utf8_lossy(bytes).into_owned();
+}
+
+/// A loaded KenLM model.
+/// A model can be shared by multiple `KenLM`s through `Arc`, e.g. one for each decoder, so that a large model is loaded into memory only once.
+pub struct KenLMModel {
+    model: *mut ctclib_kenlm_sys::lm_base_Model,
+    /// Words of the model indexed by the KenLM word index. This is empty unless `enumerate_vocab` is enabled.
+    vocabulary: Vec<String>,
+}
+
+impl KenLMModel {
+    pub fn load<T: AsRef<str>>(path: T) -> Result<Self, LMError> {
+        Self::load_with_config(path, &KenLMConfig::default())
+    }
+
+    pub fn load_with_config<T: AsRef<str>>(path: T, config: &KenLMConfig) -> Result<Self, LMError> {
+        let path = path.as_ref();
+        let x = CString::new(path).map_err(|_| LMError::InvalidPath(path.into()))?;
+        if !Path::new(path).is_file() {
+            return Err(LMError::NotFound(path.into()));
+        }
+        let write_binary = match config.write_binary {
+            Some(ref write_binary) => Some(
+                write_binary
+                    .to_str()
+                    .and_then(|path| CString::new(path).ok())
+                    .ok_or_else(|| LMError::InvalidPath(write_binary.display().to_string()))?,
+            ),
+            None => None,
+        };
+        let mut vocabulary = Vec::new();
+        let mut raw_config = unsafe { ctclib_kenlm_sys::lm_ngram_Config::new() };
+        raw_config.load_method = config.load_method.to_raw();
+        raw_config.arpa_complain = config.arpa_complain.to_raw();
+        raw_config.unknown_missing_logprob = config.unknown_missing_logprob;
+        if let Some(ref write_binary) = write_binary {
+            raw_config.write_mmap = write_binary.as_ptr();
+        }
+        if config.enumerate_vocab {
+            raw_config.enumerate_vocab = unsafe {
+                ctclib_kenlm_sys::lm_EnumerateVocab_new(
+                    Some(collect_word),
+                    &mut vocabulary as *mut Vec<String> as *mut c_void,
+                )
+            };
+        }
+        let mut error = [0 as c_char; 1024];
+        let model = unsafe {
+            ctclib_kenlm_sys::lm_ngram_LoadVirtualOrError(
+                x.as_ptr(),
+                &raw_config as *const _,
+                error.as_mut_ptr(),
+                error.len() as _,
+            )
+        };
+        if !raw_config.enumerate_vocab.is_null() {
+            unsafe { ctclib_kenlm_sys::lm_EnumerateVocab_delete(raw_config.enumerate_vocab) };
+        }
+        if model.is_null() {
+            let message = unsafe { CStr::from_ptr(error.as_ptr()) };
+            return Err(LMError::Parse(message.to_string_lossy().into_owned()));
+        }
+        Ok(Self { model, vocabulary })
+    }
+
+    /// Returns the words of the model indexed by the KenLM word index.
+    /// This is empty unless the model is loaded with `KenLMConfig::enumerate_vocab`.
+    pub fn vocabulary(&self) -> &[String] {
+        &self.vocabulary
+    }
+
+    /// Returns the entries of `dict` that are not in the vocabulary of the model, in the order of their indices.
+    /// KenLM scores them as `<unk>`.
+    pub fn oov_entries(&self, dict: &Dict) -> Vec<String> {
+        self.oov_indexed_entries(dict)
+            .into_iter()
+            .map(|(_, word)| word)
+            .collect()
+    }
 
-impl Model {
-    fn new<T: AsRef<str>>(path: T) -> Self {
-        let x = CString::new(path.as_ref()).unwrap();
-        let model = unsafe { ctclib_kenlm_sys::lm_ngram_LoadVirtualWithDefaultConfig(x.as_ptr()) };
-        Self(model)
+    /// Returns the OOV entries of `dict` with their indices, sorted by the indices.
+    fn oov_indexed_entries(&self, dict: &Dict) -> Vec<(i32, String)> {
+        let vocab = self.vocab();
+        let mut entries = dict
+            .iter()
+            .filter(|(word, _)| word.as_str() != "<unk>" && vocab.index(word) == vocab.unk())
+            .map(|(word, &idx)| (idx, word.clone()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
     }
 
     fn vocab(&self) -> Vocabulary {
         Vocabulary(
-            unsafe { ctclib_kenlm_sys::lm_base_Model_BaseVocabulary(self.0) },
+            unsafe { ctclib_kenlm_sys::lm_base_Model_BaseVocabulary(self.model) },
             self,
         )
     }
 
-    fn begin_context(&self) -> KenLMState {
-        let mut state = KenLMState::new();
+    fn begin_context(&self) -> KenLMContext {
+        let mut state = KenLMContext::new();
         state.with_mut_ptr(|ptr| unsafe {
-            ctclib_kenlm_sys::lm_base_Model_BeginSentenceWrite(self.0, ptr as *mut _)
+            ctclib_kenlm_sys::lm_base_Model_BeginSentenceWrite(self.model, ptr as *mut _)
         });
         state
     }
 
-    fn base_score(&self, state: &KenLMState, token: KenLMWordIndex) -> (KenLMState, f32) {
+    fn base_score(&self, state: &KenLMContext, token: KenLMWordIndex) -> (KenLMContext, f32) {
         state.with_ptr(|state_ptr| {
-            let mut outstate = KenLMState::new();
+            let mut outstate = KenLMContext::new();
             let score = outstate.with_mut_ptr(|out| unsafe {
                 ctclib_kenlm_sys::lm_base_Model_BaseScore(
-                    self.0,
+                    self.model,
                     state_ptr as *const _,
                     token as u32,
                     out as *mut _,
@@ -71,28 +293,62 @@ impl Model {
     }
 }
 
-impl Drop for Model {
+impl WordModel for KenLMModel {
+    type Word = KenLMWordIndex;
+    type Context = KenLMContext;
+
+    fn word_index(&self, word: &str) -> KenLMWordIndex {
+        self.vocab().index(word)
+    }
+
+    fn unk_word(&self) -> KenLMWordIndex {
+        self.vocab().unk()
+    }
+
+    fn eos_word(&self) -> KenLMWordIndex {
+        self.vocab().end_sentence()
+    }
+
+    fn start_context(&self) -> KenLMContext {
+        self.begin_context()
+    }
+
+    fn score_word(&self, context: &KenLMContext, word: KenLMWordIndex) -> (KenLMContext, f32) {
+        self.base_score(context, word)
+    }
+}
+
+// KenLM models are immutable once loaded, and querying them from multiple threads is safe.
+unsafe impl Send for KenLMModel {}
+unsafe impl Sync for KenLMModel {}
+
+impl Drop for KenLMModel {
     fn drop(&mut self) {
         unsafe {
-            ctclib_kenlm_sys::lm_base_Model_delete(self.0);
+            ctclib_kenlm_sys::lm_base_Model_delete(self.model);
         }
     }
 }
 
 /// A wrapper of a reference to KenLM Vocabulary
-struct Vocabulary<'a>(*const ctclib_kenlm_sys::lm_base_Vocabulary, &'a Model);
+struct Vocabulary<'a>(*const ctclib_kenlm_sys::lm_base_Vocabulary, &'a KenLMModel);
 
 impl<'a> Vocabulary<'a> {
     fn end_sentence(&self) -> KenLMWordIndex {
         unsafe { ctclib_kenlm_sys::lm_base_Vocabulary_EndSentence(self.0) }
     }
 
+    /// KenLM always assigns 0 to `<unk>`, and maps unknown words to it.
+    fn unk(&self) -> KenLMWordIndex {
+        0
+    }
+
     fn index(&self, x: &str) -> KenLMWordIndex {
         unsafe {
             ctclib_kenlm_sys::lm_base_Vocabulary_Index(
                 self.0,
                 x.as_ptr() as *const _,
-                x.as_bytes().len() as u64,
+                x.as_bytes().len() as ctclib_kenlm_sys::size_t,
             )
         }
     }
@@ -100,7 +356,7 @@ impl<'a> Vocabulary<'a> {
 
 #[test]
 fn load_model_and_get_vocab() {
-    let model = Model::new("data/overfit.arpa");
+    let model = KenLMModel::load("data/overfit.arpa").unwrap();
     let vocab = model.vocab();
     assert_eq!(vocab.end_sentence(), 2);
     assert_eq!(vocab.index("M"), 3);
@@ -119,30 +375,78 @@ fn load_model_and_get_vocab() {
 /// KenLM integration for ctc decoding.
 /// KenLM is a n-gram language model library written in C++.
 /// See https://github.com/kpu/kenlm for more details about KenLM itself.
+///
+/// By default, each token is scored as a word of KenLM, so the model must be trained on the same tokens as the `Dict` (e.g. a character LM).
+/// In word-level mode, tokens are accumulated until a word separator (e.g. `|` of a letter dictionary) comes, and then the spelled word is scored with the vocabulary of the model.
 pub struct KenLM {
-    model: Model,
-    idx_to_kenlm_idx: Vec<KenLMWordIndex>,
-    n_vocab: usize,
+    words: WordLevel<KenLMModel>,
 }
 
 impl KenLM {
-    pub fn new<T: AsRef<str>>(path: T, dict: &Dict) -> Self {
-        // TODO: convert user vocabulary to KenLM's vocabulary
-        let model = Model::new(path);
-        let vocab = model.vocab();
+    pub fn new<T: AsRef<str>>(path: T, dict: &Dict) -> Result<Self, LMError> {
+        Self::from_model(Arc::new(KenLMModel::load(path)?), dict)
+    }
 
-        let mut idx_to_kenlm_idx = vec![0; dict.len()];
+    /// Creates KenLM in word-level mode.
+    pub fn new_word_level<T: AsRef<str>>(
+        path: T,
+        dict: &Dict,
+        word_separator: i32,
+    ) -> Result<Self, LMError> {
+        Self::from_model_word_level(Arc::new(KenLMModel::load(path)?), dict, word_separator)
+    }
 
-        for (word, &idx) in dict.iter() {
-            let kenlm_idx = vocab.index(word);
-            idx_to_kenlm_idx[idx as usize] = kenlm_idx;
-        }
+    /// Creates KenLM like `new`, but fails with `LMError::OutOfVocabulary` if some entries of `dict` are not in the vocabulary of the model.
+    /// Tokens in `allowed_oov` (e.g. blank) are not checked.
+    pub fn new_strict<T: AsRef<str>>(
+        path: T,
+        dict: &Dict,
+        allowed_oov: &[i32],
+    ) -> Result<Self, LMError> {
+        Self::from_model_strict(Arc::new(KenLMModel::load(path)?), dict, allowed_oov)
+    }
 
-        Self {
-            model,
-            idx_to_kenlm_idx,
-            n_vocab: dict.len(),
+    /// Creates KenLM with a model that has been loaded already.
+    /// Fails with `LMError::VocabularyMismatch` if the indices of `dict` are not contiguous from 0, e.g. added by `Dict::add_entry_at`.
+    pub fn from_model(model: Arc<KenLMModel>, dict: &Dict) -> Result<Self, LMError> {
+        // TODO: convert user vocabulary to KenLM's vocabulary
+        Ok(Self {
+            words: WordLevel::new(model, dict, None)?,
+        })
+    }
+
+    /// Creates KenLM like `from_model`, but fails with `LMError::OutOfVocabulary` if some entries of `dict` are not in the vocabulary of the model.
+    /// Tokens in `allowed_oov` (e.g. blank) are not checked.
+    pub fn from_model_strict(
+        model: Arc<KenLMModel>,
+        dict: &Dict,
+        allowed_oov: &[i32],
+    ) -> Result<Self, LMError> {
+        let oov = model
+            .oov_indexed_entries(dict)
+            .into_iter()
+            .filter(|(idx, _)| !allowed_oov.contains(idx))
+            .map(|(_, word)| word)
+            .collect::<Vec<_>>();
+        if !oov.is_empty() {
+            return Err(LMError::OutOfVocabulary(oov));
         }
+        Self::from_model(model, dict)
+    }
+
+    /// Creates KenLM in word-level mode with a model that has been loaded already.
+    pub fn from_model_word_level(
+        model: Arc<KenLMModel>,
+        dict: &Dict,
+        word_separator: i32,
+    ) -> Result<Self, LMError> {
+        Ok(Self {
+            words: WordLevel::new(model, dict, Some(word_separator))?,
+        })
+    }
+
+    pub fn model(&self) -> &Arc<KenLMModel> {
+        self.words.model()
     }
 }
 
@@ -150,8 +454,7 @@ impl LM for KenLM {
     type State = KenLMState;
 
     fn start(&mut self) -> LMStateRef<Self::State> {
-        let initial_state = self.model.begin_context();
-        LMStateRef::new(initial_state)
+        self.words.start()
     }
 
     fn score(
@@ -160,20 +463,18 @@ impl LM for KenLM {
         token: i32,
         n_vocab: usize,
     ) -> (LMStateRef<Self::State>, f32) {
-        let kenlm_idx = self.idx_to_kenlm_idx[token as usize];
-        let (next_kenlm_state, score) = {
-            self.model
-                .base_score(&state.borrow_internal_state(), kenlm_idx)
-        };
-        let outstate = state.child(token, n_vocab, next_kenlm_state);
-        (outstate, score)
+        self.words.score(state, token, n_vocab)
+    }
+
+    fn is_unknown(&mut self, state: &LMStateRef<Self::State>, token: i32) -> bool {
+        self.words.is_unknown(state, token)
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        self.words.word_separator()
     }
 
     fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
-        let eos = self.model.vocab().end_sentence();
-        let (next_kenlm_state, score) =
-            { self.model.base_score(&state.borrow_internal_state(), eos) };
-        let outstate = state.child(self.n_vocab as i32, self.n_vocab, next_kenlm_state);
-        (outstate, score)
+        self.words.finish(state)
     }
 }
@@ -0,0 +1,281 @@
+use std::{any::Any, sync::Arc};
+
+use crate::LMStateRef;
+
+use super::{LMError, LM};
+
+/// The state of a language model whose type has been erased. This holds `LMStateRef<T::State>`.
+type ErasedState = Arc<dyn Any + Send + Sync>;
+
+/// LM without its state type, so that MultiLM can hold language models of different types.
+trait ErasedLM: Send {
+    fn start(&mut self) -> ErasedState;
+    fn score(&mut self, state: &ErasedState, token: i32, n_vocab: usize) -> (ErasedState, f32);
+    fn is_unknown(&mut self, state: &ErasedState, token: i32) -> bool;
+    fn word_separator(&self) -> Option<i32>;
+    fn finish(&mut self, state: &ErasedState) -> (ErasedState, f32);
+}
+
+fn downcast<S: 'static>(state: &ErasedState) -> &LMStateRef<S> {
+    state
+        .downcast_ref()
+        .expect("the state belongs to another language model")
+}
+
+impl<T> ErasedLM for T
+where
+    T: LM + Send,
+    T::State: Send + Sync + 'static,
+{
+    fn start(&mut self) -> ErasedState {
+        Arc::new(LM::start(self))
+    }
+
+    fn score(&mut self, state: &ErasedState, token: i32, n_vocab: usize) -> (ErasedState, f32) {
+        let (next_state, score) = LM::score(self, downcast(state), token, n_vocab);
+        (Arc::new(next_state), score)
+    }
+
+    fn is_unknown(&mut self, state: &ErasedState, token: i32) -> bool {
+        LM::is_unknown(self, downcast(state), token)
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        LM::word_separator(self)
+    }
+
+    fn finish(&mut self, state: &ErasedState) -> (ErasedState, f32) {
+        let (next_state, score) = LM::finish(self, downcast(state));
+        (Arc::new(next_state), score)
+    }
+}
+
+#[derive(Debug)]
+pub struct MultiState {
+    states: Vec<ErasedState>,
+    /// The score of each model for the token that leads to this state.
+    scores: Vec<f32>,
+}
+
+/// MultiLM fuses several language models of any types, each with its own weight, e.g. a character n-gram and a word n-gram.
+/// The score is the weighted sum of the scores of the models, and `BeamSearchDecoderOptions::lm_weight` still scales the sum.
+/// Decoders record the score of each model in `DecoderOutput::lm_model_scores`.
+#[derive(Default)]
+pub struct MultiLM {
+    lms: Vec<Box<dyn ErasedLM>>,
+    weights: Vec<f32>,
+}
+
+impl MultiLM {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a language model scaled by `weight`.
+    /// Fails with `LMError::InvalidArgument` if `lm` and a model already added end words with different word separators.
+    pub fn with_lm<T>(mut self, lm: T, weight: f32) -> Result<Self, LMError>
+    where
+        T: LM + Send + 'static,
+        T::State: Send + Sync + 'static,
+    {
+        if let (Some(word_separator), Some(other)) =
+            (LM::word_separator(&lm), LM::word_separator(&self))
+        {
+            if word_separator != other {
+                return Err(LMError::InvalidArgument(format!(
+                    "word separator {} differs from {} of the other language models",
+                    word_separator, other
+                )));
+            }
+        }
+        self.lms.push(Box::new(lm));
+        self.weights.push(weight);
+        Ok(self)
+    }
+
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn len(&self) -> usize {
+        self.lms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lms.is_empty()
+    }
+
+    fn weighted_sum(&self, scores: &[f32]) -> f32 {
+        scores
+            .iter()
+            .zip(self.weights.iter())
+            .map(|(score, weight)| score * weight)
+            .sum()
+    }
+}
+
+impl LM for MultiLM {
+    type State = MultiState;
+
+    fn start(&mut self) -> LMStateRef<Self::State> {
+        LMStateRef::new(MultiState {
+            states: self.lms.iter_mut().map(|lm| lm.start()).collect(),
+            scores: vec![0.0; self.lms.len()],
+        })
+    }
+
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32) {
+        let (states, scores): (Vec<_>, Vec<_>) = self
+            .lms
+            .iter_mut()
+            .zip(state.borrow_internal_state().states.iter())
+            .map(|(lm, state)| lm.score(state, token, n_vocab))
+            .unzip();
+        let score = self.weighted_sum(&scores);
+        (
+            state.child(token, n_vocab, MultiState { states, scores }),
+            score,
+        )
+    }
+
+    /// A token is unknown only if all the models do not know it.
+    fn is_unknown(&mut self, state: &LMStateRef<Self::State>, token: i32) -> bool {
+        !self.lms.is_empty()
+            && self
+                .lms
+                .iter_mut()
+                .zip(state.borrow_internal_state().states.iter())
+                .all(|(lm, state)| lm.is_unknown(state, token))
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        self.lms.iter().find_map(|lm| lm.word_separator())
+    }
+
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+        let (states, scores): (Vec<_>, Vec<_>) = self
+            .lms
+            .iter_mut()
+            .zip(state.borrow_internal_state().states.iter())
+            .map(|(lm, state)| lm.finish(state))
+            .unzip();
+        let score = self.weighted_sum(&scores);
+        (state.eos_child(MultiState { states, scores }), score)
+    }
+
+    fn model_scores(&self, state: &LMStateRef<Self::State>) -> Option<Vec<f32>> {
+        Some(state.borrow_internal_state().scores.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::MultiLM;
+    use crate::{
+        ArpaLM, ArpaModel, BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, Dict, LMError,
+        LMStateRef, ZeroLM, LM,
+    };
+
+    /// A language model that scores token 0 with `self.0` and the others with `self.1`.
+    struct TwoScoreLM(f32, f32);
+
+    impl LM for TwoScoreLM {
+        type State = ();
+
+        fn start(&mut self) -> LMStateRef<Self::State> {
+            LMStateRef::new(())
+        }
+
+        fn score(
+            &mut self,
+            state: &LMStateRef<Self::State>,
+            token: i32,
+            n_vocab: usize,
+        ) -> (LMStateRef<Self::State>, f32) {
+            let score = if token == 0 { self.0 } else { self.1 };
+            (state.child(token, n_vocab, ()), score)
+        }
+
+        fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+            (state.clone(), 0.0)
+        }
+    }
+
+    #[test]
+    fn it_fuses_weighted_scores() {
+        let mut lm = MultiLM::new()
+            .with_lm(TwoScoreLM(-1.0, -2.0), 0.5)
+            .and_then(|lm| lm.with_lm(ZeroLM, 1.0))
+            .and_then(|lm| lm.with_lm(TwoScoreLM(-4.0, -8.0), 0.25))
+            .unwrap();
+        assert_eq!(lm.len(), 3);
+        let root = lm.start();
+        let (state, score) = lm.score(&root, 1, 3);
+        assert_eq!(score, -3.0);
+        assert_eq!(lm.model_scores(&state), Some(vec![-2.0, 0.0, -8.0]));
+        assert_eq!(lm.score(&root, 1, 3).0, state);
+        assert!(!lm.is_unknown(&root, 1));
+    }
+
+    #[test]
+    fn it_finishes_without_scoring_tokens() {
+        let mut lm = MultiLM::new().with_lm(ZeroLM, 1.0).unwrap();
+        let root = lm.start();
+        let (state, score) = lm.finish(&root);
+        assert_eq!(score, 0.0);
+        assert_eq!(lm.finish(&root).0, state);
+    }
+
+    #[test]
+    fn it_records_score_of_each_lm() {
+        // Token 0 is acoustically better, but the LMs prefer token 1.
+        #[rustfmt::skip]
+        let data = &[
+            1.0, 0.5, 0.0,
+        ];
+        let options = BeamSearchDecoderOptions {
+            beam_size: 1,
+            beam_size_token: 10,
+            lm_weight: 1.0,
+            ..Default::default()
+        };
+        let lm = MultiLM::new()
+            .with_lm(TwoScoreLM(-1.0, -0.5), 0.5)
+            .and_then(|lm| lm.with_lm(TwoScoreLM(-1.0, 0.0), 1.0))
+            .unwrap();
+        let mut decoder = BeamSearchDecoder::new(options, lm).unwrap();
+        let outputs = decoder.decode(data, 1, 3, 2);
+        assert_eq!(outputs[0].tokens, vec![1]);
+        assert_eq!(outputs[0].lm_scores, vec![-0.25]);
+        assert_eq!(outputs[0].lm_model_scores, vec![vec![-0.5, 0.0]]);
+        assert_eq!(outputs[0].score, 0.25);
+    }
+
+    #[test]
+    fn it_rejects_different_word_separators() {
+        let dict = Dict::from_entries(["A", "|", "'"].iter().map(|s| s.to_string())).unwrap();
+        let arpa =
+            "\\data\\\nngram 1=3\n\n\\1-grams:\n-1.0\t<unk>\n0\t<s>\n-1.0\t</s>\n\n\\end\\\n";
+        let model = Arc::new(ArpaModel::parse(arpa.as_bytes()).unwrap());
+        let word_lm =
+            |separator| ArpaLM::from_model_word_level(model.clone(), &dict, separator).unwrap();
+        // A model without word separator, e.g. a character LM, can be fused with a word-level LM.
+        let lm = MultiLM::new()
+            .with_lm(word_lm(1), 0.5)
+            .and_then(|lm| lm.with_lm(ZeroLM, 1.0))
+            .and_then(|lm| lm.with_lm(word_lm(1), 0.5))
+            .unwrap();
+        assert_eq!(lm.word_separator(), Some(1));
+        assert!(matches!(
+            lm.with_lm(word_lm(2), 1.0),
+            Err(LMError::InvalidArgument(_))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use crate::{Dict, LMStateRef};
+
+use super::{
+    word_level::{WordLevel, WordModel, WordState},
+    LMError, LM,
+};
+
+pub type WordIndex = u32;
+
+/// The log10 probability of `<unk>` when the model does not have it. This is the same as KenLM's default.
+pub(crate) const UNKNOWN_MISSING_LOGPROB: f32 = -100.0;
+
+/// The weights of an n-gram. Probabilities are log10, the same as ARPA files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NgramWeights {
+    pub prob: f32,
+    pub backoff: f32,
+    /// True if a longer n-gram starts with this n-gram.
+    pub has_extension: bool,
+}
+
+impl NgramWeights {
+    /// Returns true if this n-gram can affect the score of the next word, so it must be kept as a context.
+    pub fn is_context(&self) -> bool {
+        self.has_extension || self.backoff != 0.0
+    }
+}
+
+/// NgramModel is a backoff n-gram model, which `NgramLM` uses to score tokens.
+pub trait NgramModel {
+    /// Returns the order of the model, e.g. 3 for a trigram model.
+    fn order(&self) -> usize;
+    /// Returns the index of `word`, or the index of `<unk>` if the model does not have it.
+    fn index(&self, word: &str) -> WordIndex;
+    fn unk(&self) -> WordIndex;
+    fn begin_sentence(&self) -> WordIndex;
+    fn end_sentence(&self) -> WordIndex;
+    /// Returns the weights of `ngram` (the oldest word first), if the model has it.
+    fn weights(&self, ngram: &[WordIndex]) -> Option<NgramWeights>;
+
+    /// Returns the entries of `dict` that are not in the vocabulary of the model, in the order of their indices.
+    fn oov_entries(&self, dict: &Dict) -> Vec<String> {
+        let unk = self.unk();
+        let mut entries = dict
+            .iter()
+            .filter(|(word, _)| word.as_str() != "<unk>" && self.index(word) == unk)
+            .map(|(word, &idx)| (idx, word.clone()))
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries.into_iter().map(|(_, word)| word).collect()
+    }
+
+    /// Returns the log10 probability of `word` following `context` (the oldest word first) with backoff,
+    /// and the context for the next word.
+    /// The next context is shortened to the longest suffix that can affect the score of the next word, as KenLM does.
+    fn score(&self, context: &[WordIndex], word: WordIndex) -> (Vec<WordIndex>, f32) {
+        let context = &context[context.len().saturating_sub(self.order() - 1)..];
+        let mut ngram = context.to_vec();
+        ngram.push(word);
+        // Find the longest n-gram that ends with `word`. The unigram always exists.
+        let (matched, mut prob) = (0..ngram.len())
+            .find_map(|start| {
+                self.weights(&ngram[start..])
+                    .map(|weights| (ngram.len() - start, weights.prob))
+            })
+            .unwrap_or((1, UNKNOWN_MISSING_LOGPROB));
+        // Back off from the contexts longer than the matched one, from the shortest.
+        for n in matched..=context.len() {
+            if let Some(weights) = self.weights(&context[context.len() - n..]) {
+                prob += weights.backoff;
+            }
+        }
+        let next_len = (1..=ngram.len().min(self.order() - 1))
+            .rev()
+            .find(|&n| {
+                self.weights(&ngram[ngram.len() - n..])
+                    .is_some_and(|weights| weights.is_context())
+            })
+            .unwrap_or(0);
+        (ngram[ngram.len() - next_len..].to_vec(), prob)
+    }
+}
+
+pub type NgramState = WordState<Vec<WordIndex>>;
+
+impl<M: NgramModel> WordModel for M {
+    type Word = WordIndex;
+    type Context = Vec<WordIndex>;
+
+    fn word_index(&self, word: &str) -> WordIndex {
+        self.index(word)
+    }
+
+    fn unk_word(&self) -> WordIndex {
+        self.unk()
+    }
+
+    fn eos_word(&self) -> WordIndex {
+        self.end_sentence()
+    }
+
+    fn start_context(&self) -> Vec<WordIndex> {
+        vec![self.begin_sentence()]
+    }
+
+    fn score_word(&self, context: &Vec<WordIndex>, word: WordIndex) -> (Vec<WordIndex>, f32) {
+        self.score(context, word)
+    }
+}
+
+/// A pure Rust n-gram language model, which works as KenLM does without its C++ dependencies.
+/// See `ArpaLM` and `BinaryNgramLM` for the models read from files.
+///
+/// By default, each token is scored as a word of the model.
+/// In word-level mode, tokens are accumulated until a word separator (e.g. `|` of a letter dictionary) comes, and then the spelled word is scored.
+pub struct NgramLM<M: NgramModel> {
+    words: WordLevel<M>,
+}
+
+impl<M: NgramModel> NgramLM<M> {
+    /// Creates NgramLM with a model that has been loaded already.
+    pub fn from_model(model: Arc<M>, dict: &Dict) -> Result<Self, LMError> {
+        Ok(Self {
+            words: WordLevel::new(model, dict, None)?,
+        })
+    }
+
+    /// Creates NgramLM in word-level mode with a model that has been loaded already.
+    pub fn from_model_word_level(
+        model: Arc<M>,
+        dict: &Dict,
+        word_separator: i32,
+    ) -> Result<Self, LMError> {
+        Ok(Self {
+            words: WordLevel::new(model, dict, Some(word_separator))?,
+        })
+    }
+
+    pub fn model(&self) -> &Arc<M> {
+        self.words.model()
+    }
+}
+
+impl<M: NgramModel> LM for NgramLM<M> {
+    type State = NgramState;
+
+    fn start(&mut self) -> LMStateRef<Self::State> {
+        self.words.start()
+    }
+
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32) {
+        self.words.score(state, token, n_vocab)
+    }
+
+    fn is_unknown(&mut self, state: &LMStateRef<Self::State>, token: i32) -> bool {
+        self.words.is_unknown(state, token)
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        self.words.word_separator()
+    }
+
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+        self.words.finish(state)
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use crate::{Dict, LMStateRef};
+
+use super::{LMError, LM};
+
+/// A model that scores words following a context, e.g. an n-gram model.
+pub(crate) trait WordModel {
+    type Word: Copy + PartialEq;
+    type Context: Clone;
+    /// Returns the index of `word`, or `unk_word` if the model does not have it.
+    fn word_index(&self, word: &str) -> Self::Word;
+    fn unk_word(&self) -> Self::Word;
+    fn eos_word(&self) -> Self::Word;
+    /// Returns the context at the beginning of a sentence.
+    fn start_context(&self) -> Self::Context;
+    /// Returns the log10 probability of `word` following `context`, and the context for the next word.
+    fn score_word(&self, context: &Self::Context, word: Self::Word) -> (Self::Context, f32);
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WordState<C> {
+    /// The words scored so far, as the model keeps them.
+    context: C,
+    /// The spelling of the word not yet scored. This is always empty unless the LM works in word-level mode.
+    partial_word: String,
+}
+
+/// Scores tokens of a `Dict` by a `WordModel`, which `KenLM` and `NgramLM` share.
+///
+/// By default, each token is scored as a word of the model.
+/// In word-level mode, tokens are accumulated until `word_separator` comes, and then the spelled word is scored.
+pub(crate) struct WordLevel<M: WordModel> {
+    model: Arc<M>,
+    idx_to_word: Vec<M::Word>,
+    /// Entries of the dictionary, which spell words in word-level mode.
+    entries: Vec<String>,
+    /// The token that ends a word in word-level mode. `None` means token-level mode.
+    word_separator: Option<i32>,
+}
+
+impl<M: WordModel> WordLevel<M> {
+    pub(crate) fn new(
+        model: Arc<M>,
+        dict: &Dict,
+        word_separator: Option<i32>,
+    ) -> Result<Self, LMError> {
+        if let Some(word_separator) = word_separator {
+            super::check_word_separator(dict, word_separator)?;
+        }
+        let entries = super::dict_entries(dict)?;
+        let idx_to_word = entries.iter().map(|word| model.word_index(word)).collect();
+        Ok(Self {
+            model,
+            idx_to_word,
+            entries,
+            word_separator,
+        })
+    }
+
+    pub(crate) fn model(&self) -> &Arc<M> {
+        &self.model
+    }
+
+    fn score_word(
+        &self,
+        state: &WordState<M::Context>,
+        word: M::Word,
+    ) -> (WordState<M::Context>, f32) {
+        let (context, score) = self.model.score_word(&state.context, word);
+        let next_state = WordState {
+            context,
+            partial_word: String::new(),
+        };
+        (next_state, score)
+    }
+
+    /// Scores the word spelled in `state`, and returns the state where the word has been consumed.
+    fn score_partial_word(&self, state: &WordState<M::Context>) -> (WordState<M::Context>, f32) {
+        if state.partial_word.is_empty() {
+            return (state.clone(), 0.0);
+        }
+        self.score_word(state, self.model.word_index(&state.partial_word))
+    }
+}
+
+impl<M: WordModel> LM for WordLevel<M> {
+    type State = WordState<M::Context>;
+
+    fn start(&mut self) -> LMStateRef<Self::State> {
+        LMStateRef::new(WordState {
+            context: self.model.start_context(),
+            partial_word: String::new(),
+        })
+    }
+
+    fn score(
+        &mut self,
+        state: &LMStateRef<Self::State>,
+        token: i32,
+        n_vocab: usize,
+    ) -> (LMStateRef<Self::State>, f32) {
+        let (next_state, score) = {
+            let word_state = state.borrow_internal_state();
+            match self.word_separator {
+                None => self.score_word(word_state, self.idx_to_word[token as usize]),
+                Some(word_separator) if token == word_separator => {
+                    self.score_partial_word(word_state)
+                }
+                Some(_) => {
+                    let mut next_state = word_state.clone();
+                    next_state
+                        .partial_word
+                        .push_str(&self.entries[token as usize]);
+                    (next_state, 0.0)
+                }
+            }
+        };
+        (state.child(token, n_vocab, next_state), score)
+    }
+
+    fn is_unknown(&mut self, state: &LMStateRef<Self::State>, token: i32) -> bool {
+        let unk = self.model.unk_word();
+        match self.word_separator {
+            None => {
+                self.idx_to_word[token as usize] == unk && self.entries[token as usize] != "<unk>"
+            }
+            Some(word_separator) if token == word_separator => {
+                let word = &state.borrow_internal_state().partial_word;
+                !word.is_empty() && word != "<unk>" && self.model.word_index(word) == unk
+            }
+            Some(_) => false,
+        }
+    }
+
+    fn word_separator(&self) -> Option<i32> {
+        self.word_separator
+    }
+
+    fn finish(&mut self, state: &LMStateRef<Self::State>) -> (LMStateRef<Self::State>, f32) {
+        let (next_state, score) = {
+            let (word_state, word_score) = self.score_partial_word(state.borrow_internal_state());
+            let (next_state, score) = self.score_word(&word_state, self.model.eos_word());
+            (next_state, word_score + score)
+        };
+        (state.eos_child(next_state), score)
+    }
+}
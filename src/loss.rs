@@ -0,0 +1,185 @@
+use crate::{aligner::ctc_labels, decoder::log_add, AlignError};
+
+/// Computes the CTC loss of `target` (a token sequence without blanks), which is the negative log-likelihood of all the paths of `target`.
+///
+/// `data` has the same `[steps, n_vocab]` layout of log probabilities as `Decoder::decode`.
+/// The loss is infinite if no path of `target` has a finite score.
+pub fn ctc_loss(
+    data: &[f32],
+    steps: usize,
+    n_vocab: usize,
+    blank_id: i32,
+    target: &[i32],
+) -> Result<f32, AlignError> {
+    let labels = ctc_labels(data, steps, n_vocab, blank_id, target)?;
+    let alphas = forward(data, steps, n_vocab, blank_id, &labels);
+    Ok(-log_likelihood(&alphas, steps, labels.len()))
+}
+
+/// Computes the CTC loss of `target` and its gradient with respect to each value of `data`.
+///
+/// The gradient of a value is the negative posterior probability that the frame emits the token, so it is zero for the tokens not in `target`.
+/// Each value is taken as an independent variable, so add `exp(data)` to the gradient to get the gradient with respect to the logits of a log-softmax.
+/// The gradient is all zero if the loss is infinite.
+pub fn ctc_loss_and_grad(
+    data: &[f32],
+    steps: usize,
+    n_vocab: usize,
+    blank_id: i32,
+    target: &[i32],
+) -> Result<(f32, Vec<f32>), AlignError> {
+    let labels = ctc_labels(data, steps, n_vocab, blank_id, target)?;
+    let n_states = labels.len();
+    let alphas = forward(data, steps, n_vocab, blank_id, &labels);
+    let log_likelihood = log_likelihood(&alphas, steps, n_states);
+    let mut grad = vec![0.0; steps * n_vocab];
+    if log_likelihood == f32::NEG_INFINITY {
+        return Ok((f32::INFINITY, grad));
+    }
+
+    // betas[s] is the log probability of the frames after t given the state s at t, not including the frame t itself.
+    let mut betas = vec![f32::NEG_INFINITY; n_states];
+    let mut next_betas = vec![f32::NEG_INFINITY; n_states];
+    betas[n_states - 1] = 0.0;
+    if n_states > 1 {
+        betas[n_states - 2] = 0.0;
+    }
+    // The log posterior of each token at the current frame.
+    let mut posteriors = vec![f32::NEG_INFINITY; n_vocab];
+    for t in (0..steps).rev() {
+        if t + 1 < steps {
+            // Each state of the next frame with the score of its own frame.
+            let emitted = (0..n_states)
+                .map(|s| next_betas[s] + data[(t + 1) * n_vocab + labels[s] as usize])
+                .collect::<Vec<_>>();
+            for s in 0..n_states {
+                let mut beta = emitted[s];
+                if s + 1 < n_states {
+                    beta = log_add(beta, emitted[s + 1]);
+                }
+                if s + 2 < n_states && labels[s + 2] != blank_id && labels[s + 2] != labels[s] {
+                    beta = log_add(beta, emitted[s + 2]);
+                }
+                betas[s] = beta;
+            }
+        }
+        for s in 0..n_states {
+            let token = labels[s] as usize;
+            posteriors[token] = log_add(posteriors[token], alphas[t * n_states + s] + betas[s]);
+        }
+        for &token in labels.iter() {
+            let token = token as usize;
+            if posteriors[token] != f32::NEG_INFINITY {
+                grad[t * n_vocab + token] = -(posteriors[token] - log_likelihood).exp();
+                posteriors[token] = f32::NEG_INFINITY;
+            }
+        }
+        std::mem::swap(&mut betas, &mut next_betas);
+    }
+    Ok((-log_likelihood, grad))
+}
+
+/// Returns the `[steps, labels.len()]` log probabilities of the paths reaching each state at each frame, including the frame itself.
+fn forward(data: &[f32], steps: usize, n_vocab: usize, blank_id: i32, labels: &[i32]) -> Vec<f32> {
+    let n_states = labels.len();
+    let score = |t: usize, s: usize| data[t * n_vocab + labels[s] as usize];
+    let mut alphas = vec![f32::NEG_INFINITY; steps * n_states];
+    alphas[0] = score(0, 0);
+    if n_states > 1 {
+        alphas[1] = score(0, 1);
+    }
+    for t in 1..steps {
+        let (prev, current) = alphas.split_at_mut(t * n_states);
+        let prev = &prev[(t - 1) * n_states..];
+        for s in 0..n_states {
+            let mut alpha = prev[s];
+            if s >= 1 {
+                alpha = log_add(alpha, prev[s - 1]);
+            }
+            if s >= 2 && labels[s] != blank_id && labels[s] != labels[s - 2] {
+                alpha = log_add(alpha, prev[s - 2]);
+            }
+            current[s] = alpha + score(t, s);
+        }
+    }
+    alphas
+}
+
+/// Returns the log probability of all the paths, which end with the last token or the blank after it.
+fn log_likelihood(alphas: &[f32], steps: usize, n_states: usize) -> f32 {
+    let last = &alphas[(steps - 1) * n_states..];
+    if n_states > 1 {
+        log_add(last[n_states - 1], last[n_states - 2])
+    } else {
+        last[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ctc_loss, ctc_loss_and_grad};
+
+    // Tokens: 0 = A, 1 = B, 2 = blank.
+    fn data() -> Vec<f32> {
+        #[rustfmt::skip]
+        let probs = [
+            0.5, 0.2, 0.3,
+            0.4, 0.1, 0.5,
+            0.2, 0.6, 0.2,
+        ];
+        probs.iter().map(|p: &f32| p.ln()).collect()
+    }
+
+    /// Sums the probabilities of all the paths that reduce to `target`.
+    fn brute_force(data: &[f32], target: &[i32]) -> f32 {
+        let mut total = 0.0;
+        for i in 0..27 {
+            let path = [i / 9, i / 3 % 3, i % 3];
+            let mut reduced = Vec::new();
+            let mut last = 2;
+            for &token in path.iter() {
+                if token != last && token != 2 {
+                    reduced.push(token);
+                }
+                last = token;
+            }
+            if reduced == target {
+                total += path
+                    .iter()
+                    .enumerate()
+                    .map(|(t, &token)| data[t * 3 + token as usize])
+                    .sum::<f32>()
+                    .exp();
+            }
+        }
+        -total.ln()
+    }
+
+    #[test]
+    fn it_sums_all_paths() {
+        let data = data();
+        for target in [vec![], vec![0], vec![0, 1], vec![0, 0], vec![1, 0, 1]] {
+            let loss = ctc_loss(&data, 3, 3, 2, &target).unwrap();
+            assert!((loss - brute_force(&data, &target)).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn it_matches_numerical_gradient() {
+        let data = data();
+        let target = [0, 1];
+        let (loss, grad) = ctc_loss_and_grad(&data, 3, 3, 2, &target).unwrap();
+        assert_eq!(loss, ctc_loss(&data, 3, 3, 2, &target).unwrap());
+        let eps = 1e-3;
+        for i in 0..data.len() {
+            let mut shifted = data.clone();
+            shifted[i] += eps;
+            let numerical = (ctc_loss(&shifted, 3, 3, 2, &target).unwrap() - loss) / eps;
+            assert!((grad[i] - numerical).abs() < 1e-2);
+        }
+        // The posteriors of each frame sum to one.
+        for t in 0..3 {
+            assert!((grad[t * 3..(t + 1) * 3].iter().sum::<f32>() + 1.0).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use ctclib::{forced_align, Decoder, DecoderOutput, Dict, GreedyDecoder};
+
+mod common;
+
+#[test]
+fn forced_align_finds_best_path_of_greedy_transcript() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let greedy = GreedyDecoder.decode(&data, steps, n_vocab, blank);
+    let target = greedy[0].tokens.clone();
+
+    // The greedy path is the best path of all, so it is the best path of its own transcript too.
+    let alignment = forced_align(&data, steps, n_vocab, blank, &target).unwrap();
+    assert_eq!(alignment.tokens, target);
+    let best_score = (0..steps)
+        .map(|t| {
+            data[t * n_vocab..(t + 1) * n_vocab]
+                .iter()
+                .copied()
+                .fold(f32::NEG_INFINITY, f32::max)
+        })
+        .sum::<f32>();
+    assert!((alignment.score - best_score).abs() < 1e-3);
+    let path = DecoderOutput {
+        tokens: alignment.path.clone(),
+        ..DecoderOutput::default()
+    };
+    assert_eq!(path.reduced_tokens(blank), target);
+    for ((&token, &start), &end) in target
+        .iter()
+        .zip(alignment.timesteps.iter())
+        .zip(alignment.end_timesteps.iter())
+    {
+        assert!(start < end);
+        assert!(alignment.path[start..end]
+            .iter()
+            .all(|&t| t == token || t == blank));
+    }
+
+    let text = "MISTER|QUILTER|IS|THE|APOSTLE|OF|THE|MIDDLE|CLASSES|AND|WE|ARE|GLAD|TO|WELCOME|HIS|GOSPEL|";
+    let target = text
+        .chars()
+        .map(|c| dict.index(&c.to_string()).unwrap())
+        .collect::<Vec<_>>();
+    let alignment = forced_align(&data, steps, n_vocab, blank, &target).unwrap();
+    assert!(alignment.score <= best_score);
+    assert!(alignment.timesteps.windows(2).all(|w| w[0] < w[1]));
+    let words = alignment.to_output().words(dict.index("|").unwrap());
+    assert_eq!(words.len(), 17);
+    assert!(words
+        .windows(2)
+        .all(|w| w[0].end_timestep <= w[1].start_timestep));
+}
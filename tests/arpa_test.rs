@@ -0,0 +1,229 @@
+use ctclib::{
+    ArpaLM, BeamSearchDecoder, BeamSearchDecoderOptions, BinaryNgramLM, BinaryNgramModel, Decoder,
+    Dict, LMError, NgramEstimator, OptionsError, PrefixBeamSearchDecoder, LM,
+};
+
+mod common;
+
+#[test]
+fn arpa_lm_scores_as_kenlm() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let mut lm = ArpaLM::new("data/overfit.arpa", &dict).unwrap();
+    let root = lm.start();
+    let (next_state, score) = lm.score(&root, dict.index("M").unwrap(), dict.len());
+    assert_eq!(score, -0.045306083);
+    {
+        let (_, score) = lm.finish(&next_state);
+        assert_eq!(score, -2.9529781);
+    }
+    {
+        let (next_state, _) = lm.score(&next_state, dict.index("I").unwrap(), dict.len());
+        let (_, score) = lm.finish(&next_state);
+        assert_eq!(score, -2.8997345);
+    }
+}
+
+#[test]
+fn arpa_lm_scores_words_at_word_boundaries() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let separator = dict.index("|").unwrap();
+    let mut lm = ArpaLM::new_word_level("data/word.arpa", &dict, separator).unwrap();
+    let spell = |lm: &mut ArpaLM, state, word: &str| {
+        word.chars().fold(state, |state, c| {
+            let (next_state, score) =
+                lm.score(&state, dict.index(&c.to_string()).unwrap(), dict.len());
+            assert_eq!(score, 0.0);
+            next_state
+        })
+    };
+    let root = lm.start();
+    let state = spell(&mut lm, root, "TO");
+    let (state, score) = lm.score(&state, separator, dict.len());
+    assert_eq!(score, -0.5);
+    let state = spell(&mut lm, state, "WELCOME");
+    let (_, score) = lm.finish(&state);
+    assert_eq!(score, -0.25 + -0.25 + -1.0);
+}
+
+#[test]
+fn decoders_penalize_trailing_unknown_words() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let separator = dict.index("|").unwrap();
+    let blank = dict.index("").unwrap();
+    let n_vocab = dict.len();
+    // One frame for each letter of "TO|XQ", where "XQ" is out of the vocabulary and has no trailing separator.
+    let letters = ["T", "O", "|", "X", "Q"];
+    let mut data = vec![-10.0; letters.len() * n_vocab];
+    for (t, letter) in letters.iter().enumerate() {
+        data[t * n_vocab + dict.index(letter).unwrap() as usize] = 0.0;
+    }
+    let options = BeamSearchDecoderOptions {
+        beam_size: 1,
+        lm_weight: 1.0,
+        unk_score: Some(-7.0),
+        word_separator: Some(separator),
+        ..Default::default()
+    };
+    let expected = letters
+        .iter()
+        .map(|letter| dict.index(letter).unwrap())
+        .collect::<Vec<_>>();
+    let lm = || ArpaLM::new_word_level("data/word.arpa", &dict, separator).unwrap();
+
+    let mut decoder = BeamSearchDecoder::new(options.clone(), lm()).unwrap();
+    let output = &decoder.decode(&data, letters.len(), n_vocab, blank)[0];
+    assert_eq!(output.tokens, expected);
+    // "TO" is scored by the LM, "XQ" by `unk_score`, and the end of the sentence after it by the LM.
+    assert_eq!(output.score, -0.5 + -7.0 + -1.0);
+
+    let mut decoder = PrefixBeamSearchDecoder::new(options, lm()).unwrap();
+    let output = &decoder.decode(&data, letters.len(), n_vocab, blank)[0];
+    assert_eq!(output.tokens, expected);
+    assert!((output.score - (-0.5 + -7.0 + -1.0)).abs() < 1e-5);
+}
+
+#[test]
+fn decoders_take_word_separator_of_lm() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let separator = dict.index("|").unwrap();
+    let blank = dict.index("").unwrap();
+    let n_vocab = dict.len();
+    let letters = ["T", "O", "|"];
+    let mut data = vec![-10.0; letters.len() * n_vocab];
+    for (t, letter) in letters.iter().enumerate() {
+        data[t * n_vocab + dict.index(letter).unwrap() as usize] = 0.0;
+    }
+    let options = BeamSearchDecoderOptions {
+        beam_size: 1,
+        word_score: 1.0,
+        ..Default::default()
+    };
+    let lm = ArpaLM::new_word_level("data/word.arpa", &dict, separator).unwrap();
+    let mut decoder = BeamSearchDecoder::new(options, lm).unwrap();
+    let output = &decoder.decode(&data, letters.len(), n_vocab, blank)[0];
+    assert_eq!(output.bonus_scores, vec![0.0, 0.0, 1.0]);
+}
+
+#[test]
+fn decoders_reject_different_word_separators() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let separator = dict.index("|").unwrap();
+    let apostrophe = dict.index("'").unwrap();
+    let options = BeamSearchDecoderOptions {
+        word_separator: Some(apostrophe),
+        ..Default::default()
+    };
+    let lm = || ArpaLM::new_word_level("data/word.arpa", &dict, separator).unwrap();
+    let expected = || {
+        Some(OptionsError::WordSeparatorMismatch {
+            options: apostrophe,
+            lm: separator,
+        })
+    };
+    assert_eq!(
+        BeamSearchDecoder::new(options.clone(), lm()).err(),
+        expected()
+    );
+    assert_eq!(
+        PrefixBeamSearchDecoder::new(options, lm()).err(),
+        expected()
+    );
+}
+
+#[test]
+fn arpa_lm_reports_load_errors() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    assert!(matches!(
+        ArpaLM::new("data/missing.arpa", &dict),
+        Err(LMError::NotFound(_))
+    ));
+    assert!(matches!(
+        ArpaLM::new("data/letter.dict", &dict),
+        Err(LMError::Parse(_))
+    ));
+}
+
+#[test]
+fn beam_search_decoder_decodes_sequence_with_arpa_lm() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let (n_vocab, data) = common::dict_logits(&dict, n_vocab, &data);
+    let blank = common::blank(&dict);
+    let mut decoder = BeamSearchDecoder::new(
+        BeamSearchDecoderOptions {
+            beam_size_token: 2000000,
+            lm_weight: 0.5,
+            ..Default::default()
+        },
+        ArpaLM::new("data/overfit.arpa", &dict).unwrap(),
+    )
+    .unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    let text = outputs[0]
+        .tokens
+        .iter()
+        .map(|&i| dict.entry(i).unwrap().as_str())
+        .collect::<String>();
+    // The same as KenLM.
+    assert_eq!(text, "MISTER|QUILTER|IS|THE|APOSTLES|OF|THE|RIDDLE|CLASSES|AND|WE|ARE|GOLAD|TO|WELCOME|HIS|GOSPEL|");
+}
+
+#[test]
+fn binary_ngram_lm_scores_as_arpa_lm() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let dir = std::env::temp_dir().join(format!("ctclib-binary-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for quantize in [false, true] {
+        let path = dir.join(format!("overfit-{}.bin", quantize));
+        BinaryNgramModel::convert_arpa("data/overfit.arpa", &path, quantize).unwrap();
+        let mut lm = BinaryNgramLM::new(&path, &dict).unwrap();
+        let root = lm.start();
+        let (next_state, score) = lm.score(&root, dict.index("M").unwrap(), dict.len());
+        assert_eq!(score, -0.045306083);
+        let (next_state, _) = lm.score(&next_state, dict.index("I").unwrap(), dict.len());
+        let (_, score) = lm.finish(&next_state);
+        assert_eq!(score, -2.8997345);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn arpa_lm_loads_estimated_model() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let tokenize = |text: &str| {
+        text.chars()
+            .map(|c| dict.index(&c.to_string()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let mut estimator = NgramEstimator::new(3, &dict).unwrap();
+    estimator
+        .add_sentence(&tokenize(
+            "MISTER|QUILTER|IS|THE|APOSTLE|OF|THE|MIDDLE|CLASSES|",
+        ))
+        .unwrap();
+    estimator
+        .add_sentence(&tokenize("AND|WE|ARE|GLAD|TO|WELCOME|HIS|GOSPEL|"))
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("ctclib-estimate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("estimated.arpa");
+    estimator
+        .write_arpa(&mut std::fs::File::create(&path).unwrap())
+        .unwrap();
+    let mut lm = ArpaLM::new(&path, &dict).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut sentence_score = |text: &str| {
+        let mut state = lm.start();
+        let mut total = 0.0;
+        for token in tokenize(text) {
+            let (next_state, score) = lm.score(&state, token, dict.len());
+            state = next_state;
+            total += score;
+        }
+        total + lm.finish(&state).1
+    };
+    let seen = sentence_score("AND|WE|ARE|GLAD|TO|WELCOME|HIS|GOSPEL|");
+    let misspelled = sentence_score("AND|WEHARE|GOLAD|TO|WECOME|HIS|GOSPEL|");
+    assert!(seen > misspelled, "{} <= {}", seen, misspelled);
+}
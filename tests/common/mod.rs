@@ -0,0 +1,37 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use std::io::BufRead;
+
+use ctclib::Dict;
+
+/// Loads the log probabilities of an acoustic model from data/logit.txt, and returns `(steps, n_vocab, data)`.
+pub fn load_logits() -> (usize, usize, Vec<f32>) {
+    let file = std::io::BufReader::new(std::fs::File::open("data/logit.txt").unwrap());
+    let lines = file.lines().collect::<Result<Vec<_>, _>>().unwrap();
+    let steps = lines.len();
+    let data = lines
+        .iter()
+        .flat_map(|line| line.split(' ').map(|x| x.parse::<f32>().unwrap()))
+        .collect::<Vec<_>>();
+    let n_vocab = data.len() / steps;
+    (steps, n_vocab, data)
+}
+
+/// Returns the blank of data/logit.txt.
+/// The empty entry at the head of letter.dict is the blank of this acoustic model.
+pub fn blank(dict: &Dict) -> i32 {
+    dict.index("").unwrap()
+}
+
+/// Drops the trailing columns of `data` that have no entry in `dict`, and returns `(n_vocab, data)`.
+/// The acoustic model pads its output with a column that letter.dict does not name, which a language model cannot score.
+pub fn dict_logits(dict: &Dict, n_vocab: usize, data: &[f32]) -> (usize, Vec<f32>) {
+    let n_entries = dict.len();
+    let data = data
+        .chunks(n_vocab)
+        .flat_map(|frame| &frame[..n_entries])
+        .copied()
+        .collect();
+    (n_entries, data)
+}
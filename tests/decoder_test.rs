@@ -1,21 +1,12 @@
 use std::io::BufRead;
 
 use ctclib::{
-    BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, Dict, GreedyDecoder, KenLM, ZeroLM,
+    BatchDecoder, BeamSearchDecoder, BeamSearchDecoderOptions, Decoder, DecoderOutput, Dict,
+    GreedyDecoder, InputError, InputKind, KenLM, Lexicon, LexiconBeamSearchDecoder,
+    PrefixBeamSearchDecoder, WordSpan, ZeroLM,
 };
 
-fn load_logits() -> (usize, usize, Vec<f32>) {
-    let file = std::io::BufReader::new(std::fs::File::open("data/logit.txt").unwrap());
-    let lines = file.lines().collect::<Result<Vec<_>, _>>().unwrap();
-    let step = lines.len();
-    let mut logits = Vec::new();
-    for line in lines {
-        let iter = line.split(' ').map(|x| x.parse::<f32>().unwrap());
-        logits.extend(iter);
-    }
-    let vocab = logits.len() / step;
-    (step, vocab, logits)
-}
+mod common;
 
 fn load_letter_dicts() -> Vec<String> {
     let file = std::io::BufReader::new(std::fs::File::open("data/letter.dict").unwrap());
@@ -24,16 +15,17 @@ fn load_letter_dicts() -> Vec<String> {
 
 #[test]
 fn greedy_decoder_decodes_sequence_greedy() {
-    let (steps, n_vocab, data) = load_logits();
+    let (steps, n_vocab, data) = common::load_logits();
     let vocab = load_letter_dicts();
-    let blank = (n_vocab - 1) as i32;
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
     let mut decoder = GreedyDecoder;
     let outputs = decoder.decode(&data, steps, n_vocab, blank);
     let output = &outputs[0];
-    let tokens = output.reduced_tokens(blank);
-    let text = tokens
-        .into_iter()
-        .map(|i| vocab[i as usize].as_str())
+    let text = output
+        .tokens
+        .iter()
+        .map(|&i| vocab[i as usize].as_str())
         .collect::<Vec<&str>>()
         .join("");
     assert_eq!(text, "MISTE|QUILTER|T|IS|TH|E|APOSTLESR|OF|THE|RIDDLE|CLASHES|AND|WEHARE|GOLADB|TO|WELCOME|HIS|GOSUPEL|N|");
@@ -41,51 +33,429 @@ fn greedy_decoder_decodes_sequence_greedy() {
 
 #[test]
 fn beam_search_decoder_decodes_sequence() {
-    let (steps, n_vocab, data) = load_logits();
+    let (steps, n_vocab, data) = common::load_logits();
     let vocab = load_letter_dicts();
-    let blank = (n_vocab - 1) as i32;
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
     let mut decoder = BeamSearchDecoder::new(
         BeamSearchDecoderOptions {
-            beam_size: 100,
             beam_size_token: 2000000,
-            beam_threshold: f32::MAX,
-            lm_weight: 0.0,
+            ..Default::default()
         },
         ZeroLM,
-    );
+    )
+    .unwrap();
     let outputs = decoder.decode(&data, steps, n_vocab, blank);
     let output = &outputs[0];
-    let tokens = output.reduced_tokens(blank);
-    let text = tokens
-        .into_iter()
-        .map(|i| vocab[i as usize].as_str())
+    let text = output
+        .tokens
+        .iter()
+        .map(|&i| vocab[i as usize].as_str())
         .collect::<Vec<&str>>()
         .join("");
-    assert_eq!(text, "MISTE|QUILTER|T|IS|TH|E|APOSTLESR|OF|THE|RIDDLE|CLASHES|AND|WEHARE|GOLADB|TO|WELCOME|HIS|GOSPEL|N|");
+    assert_eq!(text, "MISTE|QUILTER|T|IS|TH|E|APOSTLES|OF|THE|RIDDLE|CLASHES|AND|WEHARE|GOLADB|TO|WELCOME|HIS|GOSPEL|N|");
 }
 
 #[test]
 fn beam_search_decoder_decodes_sequence_with_kenlm() {
-    let (steps, n_vocab, data) = load_logits();
+    let (steps, n_vocab, data) = common::load_logits();
     let vocab = load_letter_dicts();
-    let blank = (n_vocab - 1) as i32;
     let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let (n_vocab, data) = common::dict_logits(&dict, n_vocab, &data);
     let mut decoder = BeamSearchDecoder::new(
         BeamSearchDecoderOptions {
-            beam_size: 100,
             beam_size_token: 2000000,
-            beam_threshold: f32::MAX,
             lm_weight: 0.5,
+            ..Default::default()
         },
-        KenLM::new("data/overfit.arpa", &dict),
-    );
+        KenLM::new("data/overfit.arpa", &dict).unwrap(),
+    )
+    .unwrap();
     let outputs = decoder.decode(&data, steps, n_vocab, blank);
     let output = &outputs[0];
-    let tokens = output.reduced_tokens(blank);
-    let text = tokens
-        .into_iter()
-        .map(|i| vocab[i as usize].as_str())
+    let text = output
+        .tokens
+        .iter()
+        .map(|&i| vocab[i as usize].as_str())
         .collect::<Vec<&str>>()
         .join("");
-    assert_eq!(text, "MISTE|QUILTER|T|IS|THE|APOSTLES|OF|THE|RIDDLE|CLASHES|AND|WEHARE|GOLAD|TO|WECOME|HIS|GOSPEL|");
+    assert_eq!(text, "MISTER|QUILTER|IS|THE|APOSTLES|OF|THE|RIDDLE|CLASSES|AND|WE|ARE|GOLAD|TO|WELCOME|HIS|GOSPEL|");
+}
+
+#[test]
+fn lexicon_beam_search_decoder_decodes_only_lexicon_words() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let lexicon = Lexicon::read("data/lexicon.txt", &dict).unwrap();
+    let words = (0..lexicon.len())
+        .filter_map(|i| lexicon.word(i))
+        .map(|word| word.to_string())
+        .collect::<Vec<_>>();
+    let options = BeamSearchDecoderOptions {
+        beam_size_token: 2000000,
+        ..Default::default()
+    };
+    let to_text = |output: &DecoderOutput| {
+        output
+            .tokens
+            .iter()
+            .map(|&i| dict.entry(i).unwrap().as_str())
+            .collect::<String>()
+    };
+
+    // The best path without the lexicon spells words out of the lexicon, e.g. "MISTE".
+    let mut decoder = BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap();
+    let text = to_text(&decoder.decode(&data, steps, n_vocab, blank)[0]);
+    let oov_words = text
+        .split_terminator('|')
+        .filter(|word| !words.iter().any(|w| w == word))
+        .collect::<Vec<_>>();
+    assert!(oov_words.contains(&"MISTE"));
+
+    let mut decoder = LexiconBeamSearchDecoder::new(options, lexicon, ZeroLM).unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    let text = to_text(&outputs[0]);
+    assert!(text
+        .split_terminator('|')
+        .all(|word| words.iter().any(|w| w == word)));
+    assert_eq!(text, "MISTER|QUILTER|IS|THE|APOSTLE|OF|THE|MIDDLE|CLASSES|AND|WE|ARE|GLAD|TO|WELCOME|HIS|GOSPEL|");
+}
+
+#[test]
+fn prefix_beam_search_decoder_decodes_sequence() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let mut decoder = PrefixBeamSearchDecoder::new(
+        BeamSearchDecoderOptions {
+            beam_size_token: 2000000,
+            ..Default::default()
+        },
+        ZeroLM,
+    )
+    .unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    let text = outputs[0]
+        .tokens
+        .iter()
+        .map(|&i| dict.entry(i).unwrap().as_str())
+        .collect::<Vec<&str>>()
+        .join("");
+    assert_eq!(text, "MISTE|QUILTER|T|IS|TH|E|APOSTLES|OF|THE|RIDDLE|CLASHES|AND|WEHARE|GOLADB|TO|WELCOME|HIS|GOSPEL|N|");
+    // All hypotheses have distinct label sequences.
+    let mut token_sequences = outputs.iter().map(|o| &o.tokens).collect::<Vec<_>>();
+    token_sequences.sort();
+    token_sequences.dedup();
+    assert_eq!(token_sequences.len(), outputs.len());
+}
+
+#[test]
+fn beam_search_decoder_decodes_stream_chunk_by_chunk() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let mut decoder = BeamSearchDecoder::new(
+        BeamSearchDecoderOptions {
+            beam_size_token: 2000000,
+            ..Default::default()
+        },
+        ZeroLM,
+    )
+    .unwrap();
+    let expected = decoder.decode(&data, steps, n_vocab, blank);
+    decoder.begin(blank);
+    for chunk in data.chunks(10 * n_vocab) {
+        decoder.push_frames(chunk, chunk.len() / n_vocab);
+        let partial = decoder.partial_best();
+        assert!(partial.timesteps.iter().all(|&t| t < steps));
+    }
+    assert_eq!(decoder.finish(), expected);
+
+    // Pruning the stable prefix does not change the result.
+    decoder.begin(blank);
+    let mut stable_len = 0;
+    for chunk in data.chunks(10 * n_vocab) {
+        decoder.push_frames(chunk, chunk.len() / n_vocab);
+        let stable = decoder.stable_prefix();
+        assert!(stable.tokens.len() >= stable_len);
+        assert!(expected[0].tokens.starts_with(&stable.tokens));
+        stable_len = stable.tokens.len();
+        decoder.prune_stable();
+    }
+    assert_eq!(decoder.finish()[0], expected[0]);
+}
+
+#[test]
+fn batch_decoder_decodes_utterances_in_parallel() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let options = BeamSearchDecoderOptions {
+        beam_size: 10,
+        beam_size_token: 2000000,
+        ..Default::default()
+    };
+    let lengths = vec![steps, steps / 2, steps / 3];
+    let batch_data = data.repeat(lengths.len());
+    let decoder = BatchDecoder::new(
+        || BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap(),
+        2,
+    );
+    let outputs = decoder
+        .decode_batch(&batch_data, lengths.len(), steps, n_vocab, &lengths, blank)
+        .unwrap();
+    assert_eq!(outputs.len(), lengths.len());
+    let mut single_decoder = BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap();
+    for (output, &length) in outputs.iter().zip(lengths.iter()) {
+        let expected = single_decoder.decode(&data[..length * n_vocab], length, n_vocab, blank);
+        assert_eq!(output, &expected);
+    }
+
+    // Invalid layouts are rejected before decoding.
+    assert_eq!(
+        decoder.decode_batch(&data, lengths.len(), steps, n_vocab, &lengths, blank),
+        Err(InputError::InvalidBatchShape {
+            len: data.len(),
+            batch: lengths.len(),
+            steps,
+            n_vocab
+        })
+    );
+    assert_eq!(
+        decoder.decode_batch(
+            &batch_data,
+            lengths.len(),
+            steps,
+            n_vocab,
+            &lengths[..2],
+            blank
+        ),
+        Err(InputError::InvalidLengths { len: 2, batch: 3 })
+    );
+    assert_eq!(
+        decoder.decode_batch(&batch_data, 1, steps / 2, n_vocab, &[steps], blank),
+        Err(InputError::InvalidLength {
+            index: 0,
+            length: steps,
+            steps: steps / 2
+        })
+    );
+}
+
+#[test]
+fn beam_search_decoder_can_be_moved_across_threads() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let mut decoder = BeamSearchDecoder::new(
+        BeamSearchDecoderOptions {
+            beam_size: 10,
+            beam_size_token: 2000000,
+            ..Default::default()
+        },
+        ZeroLM,
+    )
+    .unwrap();
+    let expected = decoder.decode(&data, steps, n_vocab, blank);
+    let outputs = std::thread::spawn(move || decoder.decode(&data, steps, n_vocab, blank))
+        .join()
+        .unwrap();
+    assert_eq!(outputs, expected);
+}
+
+/// Checks that the words are in order and do not overlap.
+fn assert_word_spans(words: &[WordSpan], steps: usize) {
+    assert!(words.iter().all(|w| w.start_timestep < w.end_timestep));
+    assert!(words
+        .windows(2)
+        .all(|w| w[0].end_timestep <= w[1].start_timestep));
+    assert!(words.last().unwrap().end_timestep <= steps);
+}
+
+#[test]
+fn words_take_tokens_without_confidences_as_certain() {
+    let output = DecoderOutput {
+        tokens: vec![1, 2, 0, 1],
+        timesteps: vec![0, 1, 2, 3],
+        end_timesteps: vec![1, 2, 3, 4],
+        confidences: vec![0.5],
+        ..DecoderOutput::default()
+    };
+    let words = output.words(0);
+    assert_eq!(words.len(), 2);
+    assert_eq!(words[0].confidence, 0.5);
+    assert_eq!(words[1].confidence, 1.0);
+}
+
+#[test]
+fn decoders_find_word_spans() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let separator = dict.index("|").unwrap();
+    let greedy = GreedyDecoder.decode(&data, steps, n_vocab, blank);
+    let words = greedy[0].words(separator);
+    let text = words
+        .iter()
+        .map(|word| {
+            word.tokens
+                .iter()
+                .map(|&i| dict.entry(i).unwrap().as_str())
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    assert_eq!(text, "MISTE QUILTER T IS TH E APOSTLESR OF THE RIDDLE CLASHES AND WEHARE GOLADB TO WELCOME HIS GOSUPEL N");
+    assert_word_spans(&words, steps);
+    let (start, end) = words[0].seconds(0.02);
+    assert_eq!(start, words[0].start_timestep as f32 * 0.02);
+    assert_eq!(end, words[0].end_timestep as f32 * 0.02);
+
+    let options = BeamSearchDecoderOptions {
+        beam_size_token: 2000000,
+        ..Default::default()
+    };
+    let mut decoder = BeamSearchDecoder::new(options.clone(), ZeroLM).unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    let beam_words = outputs[0].words(separator);
+    assert_word_spans(&beam_words, steps);
+    // The best path of the beam search is close to the greedy path.
+    assert_eq!(beam_words.len(), words.len());
+    assert!(beam_words
+        .iter()
+        .zip(words.iter())
+        .all(|(a, b)| a.start_timestep < b.end_timestep && b.start_timestep < a.end_timestep));
+
+    let mut decoder = PrefixBeamSearchDecoder::new(options, ZeroLM).unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    assert_word_spans(&outputs[0].words(separator), steps);
+}
+
+#[test]
+fn decoders_estimate_confidences() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let separator = dict.index("|").unwrap();
+    let options = BeamSearchDecoderOptions {
+        beam_size_token: 2000000,
+        ..Default::default()
+    };
+    let outputs = [
+        GreedyDecoder.decode(&data, steps, n_vocab, blank),
+        BeamSearchDecoder::new(options.clone(), ZeroLM)
+            .unwrap()
+            .decode(&data, steps, n_vocab, blank),
+        PrefixBeamSearchDecoder::new(options, ZeroLM)
+            .unwrap()
+            .decode(&data, steps, n_vocab, blank),
+    ];
+    for output in outputs.iter().map(|outputs| &outputs[0]) {
+        assert_eq!(output.confidences.len(), output.tokens.len());
+        for i in 0..output.tokens.len() {
+            // The confidence is the peak posterior of the token over its frames.
+            let peak = (output.timesteps[i]..output.end_timesteps[i])
+                .map(|t| data[t * n_vocab + output.tokens[i] as usize].exp())
+                .fold(0.0, f32::max);
+            assert!((output.confidences[i] - peak).abs() < 1e-6);
+            assert!(output.confidences[i] > 0.0 && output.confidences[i] <= 1.0);
+        }
+        // A word is as confident as its least confident token.
+        let mut i = 0;
+        for word in output.words(separator) {
+            while output.tokens[i] == separator {
+                i += 1;
+            }
+            let confidences = &output.confidences[i..i + word.tokens.len()];
+            assert_eq!(
+                word.confidence,
+                confidences.iter().copied().fold(1.0, f32::min)
+            );
+            i += word.tokens.len();
+        }
+    }
+}
+
+#[test]
+fn decoders_normalize_input() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let expected = GreedyDecoder.decode(&data, steps, n_vocab, blank);
+
+    // Shifted logits and probabilities are the same distribution as the log probabilities.
+    let logits = data.iter().map(|x| x + 3.0).collect::<Vec<_>>();
+    let probs = data.iter().map(|x| x.exp()).collect::<Vec<_>>();
+    for (input, kind) in [(&logits, InputKind::Logits), (&probs, InputKind::Probs)] {
+        let normalized = kind.normalize(input, n_vocab, 1.0).unwrap();
+        assert!(normalized
+            .iter()
+            .zip(data.iter())
+            .all(|(a, b)| (a - b).abs() < 1e-3 || b.exp() < 1e-6));
+        let outputs = GreedyDecoder
+            .decode_input(input, steps, n_vocab, blank, kind, 1.0)
+            .unwrap();
+        assert_eq!(outputs[0].tokens, expected[0].tokens);
+    }
+
+    // The temperature keeps each frame normalized, and the order of the tokens.
+    let normalized = InputKind::LogProbs.normalize(&data, n_vocab, 2.0).unwrap();
+    for t in 0..steps {
+        let frame = &normalized[t * n_vocab..(t + 1) * n_vocab];
+        assert!((frame.iter().map(|x| x.exp()).sum::<f32>() - 1.0).abs() < 1e-4);
+    }
+    let outputs = GreedyDecoder
+        .decode_input(&data, steps, n_vocab, blank, InputKind::LogProbs, 2.0)
+        .unwrap();
+    assert_eq!(outputs[0].tokens, expected[0].tokens);
+    assert!(outputs[0].score < expected[0].score);
+
+    // Invalid input is rejected before decoding.
+    assert_eq!(
+        InputKind::Logits.normalize(&data, n_vocab, 0.0),
+        Err(InputError::InvalidTemperature(0.0))
+    );
+    assert!(matches!(
+        InputKind::Logits.normalize(&data, 0, 1.0),
+        Err(InputError::InvalidShape { .. })
+    ));
+    assert!(matches!(
+        GreedyDecoder.decode_input(&data, steps + 1, n_vocab, blank, InputKind::LogProbs, 1.0),
+        Err(InputError::InvalidShape { .. })
+    ));
+    let mut invalid = data.clone();
+    invalid[1] = f32::NAN;
+    assert!(matches!(
+        GreedyDecoder.decode_input(&invalid, steps, n_vocab, blank, InputKind::LogProbs, 1.0),
+        Err(InputError::InvalidValue { index: 1, .. })
+    ));
+    invalid[1] = f32::INFINITY;
+    assert_eq!(
+        InputKind::Logits.normalize(&invalid, n_vocab, 1.0),
+        Err(InputError::InvalidValue {
+            index: 1,
+            value: f32::INFINITY
+        })
+    );
+    // A zero probability is negative infinity in log space.
+    invalid[1] = f32::NEG_INFINITY;
+    assert!(InputKind::LogProbs
+        .normalize(&invalid, n_vocab, 1.0)
+        .is_ok());
+    let mut probs = vec![0.5; n_vocab];
+    probs[2] = -0.5;
+    assert_eq!(
+        InputKind::Probs.normalize(&probs, n_vocab, 1.0),
+        Err(InputError::InvalidValue {
+            index: 2,
+            value: -0.5
+        })
+    );
+    probs[2] = 1.5;
+    assert!(matches!(
+        InputKind::Probs.normalize(&probs, n_vocab, 1.0),
+        Err(InputError::InvalidValue { index: 2, .. })
+    ));
 }
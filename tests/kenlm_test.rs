@@ -1,11 +1,14 @@
-use std::fs::File;
+use std::{fs::File, sync::Arc};
 
-use ctclib::{Dict, KenLM, LM};
+use ctclib::{
+    ArpaLM, ArpaLoadComplain, BeamSearchDecoder, Dict, KenLM, KenLMConfig, KenLMModel, LMError,
+    LoadMethod, NgramEstimator, LM,
+};
 
 #[test]
 fn kenlm_model_works() {
     let dict = Dict::parse(File::open("data/letter.dict").unwrap()).unwrap();
-    let mut kenlm = KenLM::new("data/overfit.arpa", &dict);
+    let mut kenlm = KenLM::new("data/overfit.arpa", &dict).unwrap();
     let root = kenlm.start();
     let (next_state, score) = kenlm.score(&root, dict.index("M").unwrap(), dict.len());
     assert_eq!(score, -0.045306083);
@@ -19,3 +22,165 @@ fn kenlm_model_works() {
         assert_eq!(score, -2.8997345);
     }
 }
+
+#[test]
+fn kenlm_scores_words_at_word_boundaries() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let separator = dict.index("|").unwrap();
+    let mut kenlm = KenLM::new_word_level("data/word.arpa", &dict, separator).unwrap();
+    let spell = |kenlm: &mut KenLM, state, word: &str| {
+        word.chars().fold(state, |state, c| {
+            let (next_state, score) =
+                kenlm.score(&state, dict.index(&c.to_string()).unwrap(), dict.len());
+            assert_eq!(score, 0.0);
+            next_state
+        })
+    };
+    let root = kenlm.start();
+    let state = spell(&mut kenlm, root, "TO");
+    let (state, score) = kenlm.score(&state, separator, dict.len());
+    assert_eq!(score, -0.5);
+    // The last word is scored on finish even without a separator.
+    let state = spell(&mut kenlm, state, "WELCOME");
+    let (_, score) = kenlm.finish(&state);
+    assert_eq!(score, -0.25 + -0.25 + -1.0);
+}
+
+#[test]
+fn kenlm_decoder_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<KenLM>();
+    assert_send::<BeamSearchDecoder<KenLM>>();
+}
+
+#[test]
+fn kenlm_model_can_be_shared_across_threads() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let model = Arc::new(KenLMModel::load("data/overfit.arpa").unwrap());
+    let workers = (0..2)
+        .map(|_| {
+            let mut kenlm = KenLM::from_model(model.clone(), &dict).unwrap();
+            let token = dict.index("M").unwrap();
+            let n_vocab = dict.len();
+            std::thread::spawn(move || {
+                let root = kenlm.start();
+                kenlm.score(&root, token, n_vocab).1
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        assert_eq!(worker.join().unwrap(), -0.045306083);
+    }
+}
+
+#[test]
+fn kenlm_reports_load_errors() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    assert!(matches!(
+        KenLM::new("data/missing.arpa", &dict),
+        Err(LMError::NotFound(_))
+    ));
+    assert!(matches!(
+        KenLM::new("data/overfit\0.arpa", &dict),
+        Err(LMError::InvalidPath(_))
+    ));
+    assert!(matches!(
+        KenLM::new("data/letter.dict", &dict),
+        Err(LMError::Parse(_))
+    ));
+    let mut sparse = Dict::new();
+    sparse.add_entry_at("M".to_string(), 5).unwrap();
+    assert!(matches!(
+        KenLM::new("data/overfit.arpa", &sparse),
+        Err(LMError::VocabularyMismatch(_))
+    ));
+    assert!(matches!(
+        KenLM::new_word_level("data/word.arpa", &dict, dict.len() as i32),
+        Err(LMError::VocabularyMismatch(_))
+    ));
+}
+
+#[test]
+fn kenlm_model_loads_with_config() {
+    let config = KenLMConfig::new()
+        .load_method(LoadMethod::Read)
+        .arpa_complain(ArpaLoadComplain::None)
+        .enumerate_vocab(true);
+    let model = Arc::new(KenLMModel::load_with_config("data/overfit.arpa", &config).unwrap());
+    assert!(model.vocabulary().iter().any(|word| word == "M"));
+    assert!(model.vocabulary().iter().any(|word| word == "</s>"));
+
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let mut kenlm = KenLM::from_model(model, &dict).unwrap();
+    let root = kenlm.start();
+    let (_, score) = kenlm.score(&root, dict.index("M").unwrap(), dict.len());
+    assert_eq!(score, -0.045306083);
+}
+
+#[test]
+fn kenlm_loads_binary_model() {
+    let dir = std::env::temp_dir().join(format!("ctclib-kenlm-binary-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("overfit.klm");
+    let config = KenLMConfig::new().write_binary(&path);
+    KenLMModel::load_with_config("data/overfit.arpa", &config).unwrap();
+    let model = KenLMModel::load(path.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let mut kenlm = KenLM::from_model(Arc::new(model.unwrap()), &dict).unwrap();
+    let root = kenlm.start();
+    let (next_state, score) = kenlm.score(&root, dict.index("M").unwrap(), dict.len());
+    assert_eq!(score, -0.045306083);
+    let (_, score) = kenlm.finish(&next_state);
+    assert_eq!(score, -2.9529781);
+}
+
+#[test]
+fn kenlm_reports_oov_entries() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let oov = vec!["", "Y", "B", "V", "K", "'", "X", "J"];
+    let model = Arc::new(KenLMModel::load("data/overfit.arpa").unwrap());
+    assert_eq!(model.oov_entries(&dict), oov);
+
+    let blank = dict.index("").unwrap();
+    match KenLM::from_model_strict(model.clone(), &dict, &[blank]) {
+        Err(LMError::OutOfVocabulary(entries)) => assert_eq!(entries, &oov[1..]),
+        _ => panic!("strict mode must reject OOV entries"),
+    }
+
+    let known = Dict::from_entries(["M", "I", "S", "|"].iter().map(|s| s.to_string())).unwrap();
+    assert!(KenLM::from_model_strict(model, &known, &[]).is_ok());
+}
+
+#[test]
+fn kenlm_loads_estimated_model() {
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let tokens = "MISTER|QUILTER|IS|THE|APOSTLE|OF|THE|MIDDLE|CLASSES|"
+        .chars()
+        .map(|c| dict.index(&c.to_string()).unwrap())
+        .collect::<Vec<_>>();
+    let mut estimator = NgramEstimator::new(3, &dict).unwrap();
+    estimator.add_sentence(&tokens).unwrap();
+    let dir = std::env::temp_dir().join(format!("ctclib-kenlm-estimate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("estimated.arpa");
+    estimator
+        .write_arpa(&mut File::create(&path).unwrap())
+        .unwrap();
+    let mut kenlm = KenLM::new(path.to_str().unwrap(), &dict).unwrap();
+    let mut arpa_lm = ArpaLM::new(&path, &dict).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (mut kenlm_state, mut arpa_state) = (kenlm.start(), arpa_lm.start());
+    for &token in tokens.iter() {
+        let (next_state, kenlm_score) = kenlm.score(&kenlm_state, token, dict.len());
+        kenlm_state = next_state;
+        let (next_state, arpa_score) = arpa_lm.score(&arpa_state, token, dict.len());
+        arpa_state = next_state;
+        assert!((kenlm_score - arpa_score).abs() < 1e-5);
+    }
+    let (_, kenlm_score) = kenlm.finish(&kenlm_state);
+    let (_, arpa_score) = arpa_lm.finish(&arpa_state);
+    assert!((kenlm_score - arpa_score).abs() < 1e-5);
+}
@@ -0,0 +1,52 @@
+use ctclib::{
+    ctc_loss, ctc_loss_and_grad, BeamSearchDecoderOptions, Decoder, Dict, PrefixBeamSearchDecoder,
+    ZeroLM,
+};
+
+mod common;
+
+#[test]
+fn ctc_loss_bounds_prefix_beam_search_scores() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let mut decoder = PrefixBeamSearchDecoder::new(
+        BeamSearchDecoderOptions {
+            beam_size_token: 2000000,
+            ..Default::default()
+        },
+        ZeroLM,
+    )
+    .unwrap();
+    let outputs = decoder.decode(&data, steps, n_vocab, blank);
+    for output in outputs.iter().take(10) {
+        // The prefix beam search sums the paths surviving in the beam, which are a part of all the paths.
+        let log_likelihood = -ctc_loss(&data, steps, n_vocab, blank, &output.tokens).unwrap();
+        assert!(output.score <= log_likelihood + 1e-3);
+        // But they carry most of the probability.
+        assert!(output.score > log_likelihood - 1.0);
+    }
+}
+
+#[test]
+fn ctc_loss_and_grad_returns_posteriors() {
+    let (steps, n_vocab, data) = common::load_logits();
+    let dict = Dict::read("data/letter.dict").unwrap();
+    let blank = common::blank(&dict);
+    let text = "MISTER|QUILTER|IS|THE|APOSTLE|OF|THE|MIDDLE|CLASSES|AND|WE|ARE|GLAD|TO|WELCOME|HIS|GOSPEL|";
+    let target = text
+        .chars()
+        .map(|c| dict.index(&c.to_string()).unwrap())
+        .collect::<Vec<_>>();
+    let (loss, grad) = ctc_loss_and_grad(&data, steps, n_vocab, blank, &target).unwrap();
+    assert_eq!(
+        loss,
+        ctc_loss(&data, steps, n_vocab, blank, &target).unwrap()
+    );
+    assert!(loss.is_finite() && loss > 0.0);
+    // Each frame emits exactly one of the labels.
+    for t in 0..steps {
+        let total = grad[t * n_vocab..(t + 1) * n_vocab].iter().sum::<f32>();
+        assert!((total + 1.0).abs() < 1e-3);
+    }
+}